@@ -0,0 +1,155 @@
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+
+use crate::rom_loader::RomError;
+
+// Extensions we pick out of an archive when no entry name is given
+const ROM_EXTENSIONS: [&str; 4] = ["nes", "unf", "unif", "fds"];
+
+// Extensions of the archives `read_rom_image` unpacks
+const ARCHIVE_EXTENSIONS: [&str; 2] = ["zip", "gz"];
+
+const ZIP_MAGIC: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+// Largest image we unpack; well above any real cartridge, low enough that a
+// crafted archive can't make us allocate gigabytes
+const MAX_UNPACKED_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Read a ROM image from disk, unpacking it first if it is a .zip or .gz
+/// archive. `entry` selects a file inside a zip by name, otherwise the
+/// first .nes/.unf/.fds entry is used.
+pub fn read_rom_image(path: &str, entry: Option<&str>) -> Result<Vec<u8>, RomError> {
+    let data = fs::read(path)?;
+
+    if data.starts_with(&ZIP_MAGIC) {
+        unzip(&data, entry)
+    } else if data.starts_with(&GZIP_MAGIC) {
+        gunzip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+fn gunzip(data: &[u8]) -> Result<Vec<u8>, RomError> {
+    read_limited(GzDecoder::new(data), 0)
+}
+
+fn unzip(data: &[u8], entry: Option<&str>) -> Result<Vec<u8>, RomError> {
+    let mut zip =
+        zip::ZipArchive::new(Cursor::new(data)).map_err(|e| RomError::Archive(e.to_string()))?;
+
+    let name = match entry {
+        Some(name) => name.to_string(),
+        None => zip
+            .file_names()
+            .find(|name| is_rom_name(name))
+            .map(str::to_string)
+            .ok_or(RomError::NoRomInArchive)?,
+    };
+
+    let file = zip.by_name(&name).map_err(|e| match e {
+        zip::result::ZipError::FileNotFound => RomError::ArchiveEntryNotFound(name.clone()),
+        e => RomError::Archive(e.to_string()),
+    })?;
+    // The declared size is only a capacity hint, the read itself is capped
+    let size_hint = file.size().min(MAX_UNPACKED_SIZE);
+    read_limited(file, size_hint)
+}
+
+fn read_limited(reader: impl Read, size_hint: u64) -> Result<Vec<u8>, RomError> {
+    let mut out = Vec::with_capacity(size_hint as usize);
+    reader.take(MAX_UNPACKED_SIZE + 1).read_to_end(&mut out)?;
+    if out.len() as u64 > MAX_UNPACKED_SIZE {
+        return Err(RomError::Archive(format!(
+            "unpacked image is larger than {} bytes",
+            MAX_UNPACKED_SIZE
+        )));
+    }
+    Ok(out)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    has_extension(Path::new(name), &ROM_EXTENSIONS)
+}
+
+/// Whether `path` names a .zip or .gz archive
+pub fn is_archive_name(path: &Path) -> bool {
+    has_extension(path, &ARCHIVE_EXTENSIONS)
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("nesboy-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_read_gzip() {
+        let path = temp_path("rom.nes.gz");
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(b"NES\x1Arom").unwrap();
+        fs::write(&path, gz.finish().unwrap()).unwrap();
+
+        assert_eq!(read_rom_image(&path, None).unwrap(), b"NES\x1Arom");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_zip() {
+        let path = temp_path("roms.zip");
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("readme.txt", options).unwrap();
+        zip.write_all(b"hello").unwrap();
+        zip.start_file("Game.nsf", options).unwrap(); // not a cartridge
+        zip.write_all(b"NESM\x1A").unwrap();
+        zip.start_file("Game (U).NES", options).unwrap();
+        zip.write_all(b"first").unwrap();
+        zip.start_file("Game (E).nes", options).unwrap();
+        zip.write_all(b"second").unwrap();
+        fs::write(&path, zip.finish().unwrap().into_inner()).unwrap();
+
+        assert_eq!(read_rom_image(&path, None).unwrap(), b"first");
+        assert_eq!(
+            read_rom_image(&path, Some("Game (E).nes")).unwrap(),
+            b"second"
+        );
+        assert!(matches!(
+            read_rom_image(&path, Some("missing.nes")),
+            Err(RomError::ArchiveEntryNotFound(_))
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_oversized_gzip() {
+        let path = temp_path("big.nes.gz");
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let image = vec![0; MAX_UNPACKED_SIZE as usize + 1];
+        gz.write_all(&image).unwrap();
+        fs::write(&path, gz.finish().unwrap()).unwrap();
+
+        assert!(matches!(
+            read_rom_image(&path, None),
+            Err(RomError::Archive(_))
+        ));
+        fs::remove_file(path).unwrap();
+    }
+}
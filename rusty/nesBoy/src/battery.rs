@@ -0,0 +1,77 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// .sav file backing battery-powered cartridge RAM
+pub struct BatteryRam {
+    path: PathBuf,
+    dirty: bool,
+}
+
+impl BatteryRam {
+    /// game.nes (or game.zip) saves to game.sav next to it
+    pub fn for_rom(rom_path: &str) -> Self {
+        BatteryRam {
+            path: Path::new(rom_path).with_extension("sav"),
+            dirty: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Fill `ram` from the .sav file, leaving it untouched if there is none yet
+    pub fn load(&self, ram: &mut [u8]) -> io::Result<()> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let len = data.len().min(ram.len());
+        ram[..len].copy_from_slice(&data[..len]);
+        Ok(())
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Write `ram` out if it changed since the last flush. Goes through a
+    /// temporary file so a crash mid-write can't eat the old save.
+    pub fn flush(&mut self, ram: &[u8]) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let tmp = self.path.with_extension("sav.tmp");
+        fs::write(&tmp, ram)?;
+        fs::rename(&tmp, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_flush_and_load() {
+        let rom_path = std::env::temp_dir().join(format!("nesboy-{}-game.nes", std::process::id()));
+        let mut battery = BatteryRam::for_rom(rom_path.to_str().unwrap());
+        assert_eq!(battery.path().extension().unwrap(), "sav");
+
+        // Nothing written until something changes
+        let mut ram = vec![0x11; 8192];
+        battery.flush(&ram).unwrap();
+        assert!(!battery.path().exists());
+
+        battery.mark_dirty();
+        battery.flush(&ram).unwrap();
+
+        ram.fill(0);
+        battery.load(&mut ram).unwrap();
+        assert!(ram.iter().all(|&b| b == 0x11));
+        fs::remove_file(battery.path()).unwrap();
+    }
+}
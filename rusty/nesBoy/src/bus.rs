@@ -1,50 +1,198 @@
 // use cpu;
+use std::io;
+use std::path::Path;
+
+use crate::battery::BatteryRam;
+use crate::fds::{FDS_BIOS_SIZE, Fds};
+use crate::mmc1::Mmc1;
 use crate::ppu::Ppu;
-use crate::rom_loader::RomLoader;
+use crate::rom_loader::{RomError, RomLoader};
+
+// Mappers the bus knows how to lay out in CPU address space
+const SUPPORTED_MAPPERS: [u16; 3] = [0, 1, 20];
+
+// Trainers sit at $7000-$71FF, i.e. 0x1000 into cartridge RAM at $6000
+const TRAINER_OFFSET: usize = 0x1000;
 
 pub struct Bus {
     // cpu: *mut Cpu, // The CPU object
     pub ppu: Ppu,
-    prg_rom: [u8; 32768], // PRG-ROM data
     prg_ram: [u8; 2048],  // PRG-RAM (work RAM)
+    cart_ram: Vec<u8>,    // Cartridge RAM at $6000-$7FFF, sized from the header
+    battery: Option<BatteryRam>,
+    fds: Option<Fds>, // Disk System RAM adapter, owns $4020-$40FF and $6000-$FFFF
+    mmc1: Option<Mmc1>, // mapper 1 PRG/CHR banking
+    cycles: u64,      // CPU cycles since power-on
+    oam_dma_pending: bool,
     pub rom: RomLoader,
 }
 
 impl Bus {
-    pub fn new() -> Self {
-        let rom = RomLoader::new("nestest.nes").unwrap();
-        let mut prg_rom = [0u8; 32768];
+    pub fn new(rom: RomLoader) -> Result<Self, RomError> {
+        if !SUPPORTED_MAPPERS.contains(&rom.mapper()) {
+            return Err(RomError::UnsupportedMapper(rom.mapper()));
+        }
+        // FDS runs from RAM, every cartridge needs something at the reset vector
+        if rom.prg_rom().is_empty() && rom.fds_image().is_none() {
+            return Err(RomError::MissingPrg);
+        }
 
-        // Copy ROM data into prg_rom array
-        let rom_data = rom.prg_rom();
-        let copy_len = rom_data.len().min(32768);
-        prg_rom[..copy_len].copy_from_slice(&rom_data[..copy_len]);
+        // Boards without CHR-ROM get CHR-RAM in its place
+        let chr_ram = rom.chr_rom().is_empty();
+        let chr = if chr_ram {
+            vec![0; rom.header().chr_ram_size.max(0x2000)]
+        } else {
+            rom.chr_rom().to_vec()
+        };
+        let ppu = Ppu::new(rom.mirroring(), chr, chr_ram);
+        let fds = rom.fds_image().map(Fds::new);
+        let mmc1 = (rom.mapper() == 1).then(|| Mmc1::new(rom.prg_rom().len()));
 
-        let ppu = Ppu::new(rom.vertical_mirroring, rom.chr_rom.clone());
+        // Power-on: trainer is copied into cartridge RAM before reset
+        let mut cart_ram_size = rom.header().prg_ram_size + rom.header().prg_nvram_size;
+        if rom.trainer().is_some() {
+            cart_ram_size = cart_ram_size.max(0x2000);
+        }
+        let mut cart_ram = vec![0u8; cart_ram_size];
+        if let Some(trainer) = rom.trainer() {
+            cart_ram[TRAINER_OFFSET..TRAINER_OFFSET + trainer.len()].copy_from_slice(trainer);
+        }
 
-        Bus {
-            prg_rom,
+        Ok(Bus {
             prg_ram: [0; 2048],
+            cart_ram,
+            battery: None,
+            fds,
+            mmc1,
+            cycles: 0,
+            oam_dma_pending: false,
             ppu,
             rom,
+        })
+    }
+
+    pub fn is_fds(&self) -> bool {
+        self.fds.is_some()
+    }
+
+    /// Load the user's Disk System BIOS (disksys.rom) into $E000-$FFFF
+    pub fn load_fds_bios(&mut self, path: &str) -> Result<(), RomError> {
+        let bios = std::fs::read(path)?;
+        if bios.len() != FDS_BIOS_SIZE {
+            return Err(RomError::BadFdsBios(bios.len()));
+        }
+        if let Some(fds) = &mut self.fds {
+            fds.load_bios(bios);
+        }
+        Ok(())
+    }
+
+    /// Flip the disk to its next side (FDS only), returning the new side
+    pub fn switch_disk_side(&mut self) -> Option<usize> {
+        self.fds.as_mut().and_then(Fds::switch_side)
+    }
+
+    /// Advance cartridge hardware by the given number of CPU cycles
+    pub fn tick(&mut self, cycles: u16) {
+        self.cycles += cycles as u64;
+        if let Some(fds) = &mut self.fds {
+            for _ in 0..cycles {
+                fds.clock();
+            }
         }
     }
 
-    pub fn read(&mut self, mut addr: u16) -> u8 {
+    /// CPU cycles the last $4014 write halts the CPU for: 513, plus one to
+    /// align when the DMA starts on an odd cycle. DMC sample fetches that
+    /// land during the copy will add their own stolen cycles here.
+    pub fn take_dma_stall(&mut self) -> u16 {
+        if !self.oam_dma_pending {
+            return 0;
+        }
+        self.oam_dma_pending = false;
+        513 + (self.cycles % 2) as u16
+    }
+
+    // $4014: copy CPU page $XX00-$XXFF into OAM
+    fn oam_dma(&mut self, page: u8) {
+        let mut data = [0u8; 256];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.read((page as u16) << 8 | i as u16);
+        }
+        self.ppu.write_oam_dma(&data);
+        self.oam_dma_pending = true;
+    }
+
+    /// Whether the PPU raised an NMI since the last poll
+    pub fn poll_nmi(&mut self) -> bool {
+        self.ppu.poll_nmi()
+    }
+
+    /// Level of the cartridge IRQ line
+    pub fn irq(&self) -> bool {
+        self.fds.as_ref().map(|fds| fds.irq()).unwrap_or(false)
+    }
+
+    /// Cartridge expansion audio level in 0.0..=1.0 (the FDS wavetable channel)
+    #[allow(dead_code)] // read by the APU mixer, which isn't written yet
+    pub fn expansion_audio(&self) -> f32 {
+        self.fds.as_ref().map_or(0.0, |fds| fds.audio.output())
+    }
+
+    /// Back cartridge RAM with a .sav file if the cartridge has a battery,
+    /// returning its path. FDS disks are saved the same way so the original
+    /// image stays untouched.
+    pub fn attach_battery(&mut self, rom_path: &str) -> io::Result<Option<&Path>> {
+        if let Some(fds) = &mut self.fds {
+            return fds.attach_save(rom_path).map(Some);
+        }
+        if !self.rom.has_battery() || self.cart_ram.is_empty() {
+            return Ok(None);
+        }
+        let battery = BatteryRam::for_rom(rom_path);
+        battery.load(&mut self.cart_ram)?;
+        Ok(Some(self.battery.insert(battery).path()))
+    }
+
+    fn cart_ram_enabled(&self) -> bool {
+        !self.cart_ram.is_empty() && self.mmc1.as_ref().is_none_or(Mmc1::prg_ram_enabled)
+    }
+
+    /// Write battery RAM to disk if it changed
+    pub fn flush_battery(&mut self) -> io::Result<()> {
+        if let Some(fds) = &mut self.fds {
+            return fds.flush_save();
+        }
+        match &mut self.battery {
+            Some(battery) => battery.flush(&self.cart_ram),
+            None => Ok(()),
+        }
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
         // Implementation of read method
-        if addr >= 0x8000 && addr <= 0xFFFF {
-            addr -= 0x8000;
-            if self.rom.prg_rom().len() == 0x4000 && addr >= 0x4000 {
-                //mirror if needed
-                addr = addr % 0x4000;
+        if let Some(value) = self.fds.as_mut().and_then(|fds| fds.read(addr)) {
+            return value;
+        }
+        if addr >= 0x8000 && let Some(mmc1) = &self.mmc1 {
+            return self.rom.prg_rom[mmc1.prg_offset(addr)];
+        }
+        if addr >= 0x8000 {
+            // PRG-ROM smaller than 32K (NROM-128, NES 2.0 odd sizes) mirrors
+            let prg_rom = self.rom.prg_rom();
+            return prg_rom[(addr - 0x8000) as usize % prg_rom.len()];
+        }
+        if (0x6000..=0x7FFF).contains(&addr) {
+            if !self.cart_ram_enabled() {
+                return 0;
             }
-            return self.rom.prg_rom[addr as usize];
+            let len = self.cart_ram.len();
+            return self.cart_ram[(addr - 0x6000) as usize % len]; // mirror smaller RAM
         }
-        if addr >= 0x2000 && addr <= 0x3FFF {
-            // return self.ppu.read(0x2000 + (addr % 8)); // Mirroring every 8 bytes
-            return 0 as u8;
+        if (0x2000..=0x3FFF).contains(&addr) {
+            return self.ppu.read(0x2000 + (addr % 8)); // Mirroring every 8 bytes
         }
-        if addr >= 0x0000 && addr <= 0x1FFF {
+        if addr <= 0x1FFF {
             return self.prg_ram[(addr % 0x0800) as usize]; // mirror every 2KB
         }
         0
@@ -52,25 +200,146 @@ impl Bus {
 
     pub fn write(&mut self, addr: u16, data: u8) {
         // Implementation of write method
+        if let Some(fds) = &mut self.fds
+            && fds.write(addr, data)
+        {
+            if addr == 0x4025 {
+                self.ppu.set_mirroring(fds.mirroring());
+            }
+            return;
+        }
         match addr {
             0x0000..= 0x1FFF => {
             self.prg_ram[(addr % 0x0800) as usize] = data;
             }
-            0x2000 => {
-                self.ppu.write_control(data);
+            0x6000..=0x7FFF if self.cart_ram_enabled() => {
+                let len = self.cart_ram.len();
+                self.cart_ram[(addr - 0x6000) as usize % len] = data;
+                if let Some(battery) = &mut self.battery {
+                    battery.mark_dirty();
+                }
             }
-            0x2006 => {
-                self.ppu.write_to_ppu_addr(data);
+            0x2000..=0x3FFF => {
+                self.ppu.write(0x2000 + (addr % 8), data); // Mirroring every 8 bytes
             }
-            0x2007 => {
-                self.ppu.write_data(data);
-            } 
-            _ => {
-                println!("Ignoring mem write-access at {}", addr);
+            0x4014 => self.oam_dma(data),
+            0x8000..=0xFFFF => {
+                if let Some(mmc1) = &mut self.mmc1 {
+                    mmc1.write(addr, data);
+                    self.ppu.set_mirroring(mmc1.mirroring());
+                    self.ppu.set_chr_banks(mmc1.chr_banks());
+                }
             }
-        // else if addr >= 0x2000 && addr <= 0x3FFF {
-        //     // self.ppu.write(0x2000 + (addr % 8), data); // Mirroring every 8 bytes
-        // }
+            _ => {} // APU, controller ports and disabled cartridge RAM
+        }
         // ROM is read-only in NES
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nrom_bus() -> Bus {
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 1, 1];
+        data.resize(16 + 16384 + 8192, 0);
+        Bus::new(RomLoader::from_bytes(&data).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_prg_rom_mirroring() {
+        // NES 2.0 exponent-multiplier size: 3K of PRG-ROM
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 0x29, 1, 0, 0x08, 0, 0x0F];
+        data.resize(16, 0);
+        data.extend((0..3072).map(|i| (i / 1024) as u8));
+        data.resize(16 + 3072 + 8192, 0);
+        let mut bus = Bus::new(RomLoader::from_bytes(&data).unwrap()).unwrap();
+        assert_eq!(bus.read(0x8000 + 2048), 2);
+        assert_eq!(bus.read(0x8000 + 3072), 0);
+        assert_eq!(bus.read(0xFFFC), bus.read(0x8000 + 0x7FFC % 3072));
+
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 0, 1];
+        data.resize(16 + 8192, 0);
+        assert!(matches!(
+            Bus::new(RomLoader::from_bytes(&data).unwrap()),
+            Err(RomError::MissingPrg)
+        ));
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut bus = nrom_bus();
+        for i in 0..256u16 {
+            bus.write(0x0200 + i, i as u8);
+        }
+        bus.write(0x2003, 0x04);
+        bus.write(0x4014, 0x02);
+
+        // Copy starts at OAMADDR and wraps around
+        assert_eq!(bus.ppu.oam_data[4], 0x00);
+        assert_eq!(bus.ppu.oam_data[5], 0x01);
+        assert_eq!(bus.ppu.oam_data[3], 0xFF);
+        assert_eq!(bus.ppu.oam_data[6], 0x02 & 0xE3); // attribute byte
+        assert_eq!(bus.ppu.oam_addr, 0x04);
+
+        assert_eq!(bus.take_dma_stall(), 513);
+        assert_eq!(bus.take_dma_stall(), 0);
+
+        bus.tick(3);
+        bus.write(0x4014, 0x02);
+        assert_eq!(bus.take_dma_stall(), 514);
+    }
+
+    #[test]
+    fn test_battery_save() {
+        // NROM with the battery bit set: 8K of cartridge RAM backed by a .sav
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0x02];
+        data.resize(16 + 16384 + 8192, 0);
+        let rom_path =
+            std::env::temp_dir().join(format!("nesboy-{}-battery.nes", std::process::id()));
+        let rom_path = rom_path.to_str().unwrap();
+
+        let mut bus = Bus::new(RomLoader::from_bytes(&data).unwrap()).unwrap();
+        let save_path = bus.attach_battery(rom_path).unwrap().unwrap().to_path_buf();
+        bus.write(0x6000, 0x42);
+        bus.write(0x7FFF, 0x24);
+        bus.flush_battery().unwrap();
+
+        let mut bus = Bus::new(RomLoader::from_bytes(&data).unwrap()).unwrap();
+        assert_eq!(bus.attach_battery(rom_path).unwrap(), Some(save_path.as_path()));
+        assert_eq!(bus.read(0x6000), 0x42);
+        assert_eq!(bus.read(0x7FFF), 0x24);
+
+        std::fs::remove_file(save_path).unwrap();
+    }
+
+    #[test]
+    fn test_mmc1_battery_save() {
+        // Final Fantasy: MMC1 (SNROM) with battery-backed PRG-RAM
+        let rom_path = std::env::temp_dir().join(format!("nesboy-{}-ff.nes", std::process::id()));
+        let rom_path = rom_path.to_str().unwrap();
+        std::fs::copy("ff.nes", rom_path).unwrap();
+
+        let mut bus = Bus::new(RomLoader::new(rom_path).unwrap()).unwrap();
+        let save_path = bus.attach_battery(rom_path).unwrap().unwrap().to_path_buf();
+        bus.write(0x6000, 0x42);
+        bus.write(0x7FFF, 0x24);
+        bus.flush_battery().unwrap();
+
+        // Switch $8000 to bank 1, the fixed last bank stays at $C000
+        for i in 0..5 {
+            bus.write(0xE000, (1 >> i) & 1);
+        }
+        let prg = bus.rom.prg_rom().to_vec();
+        assert_eq!(bus.read(0x8000), prg[0x4000]);
+        assert_eq!(bus.read(0xFFFC), prg[prg.len() - 4]);
+
+        let mut bus = Bus::new(RomLoader::new(rom_path).unwrap()).unwrap();
+        bus.attach_battery(rom_path).unwrap();
+        assert_eq!(bus.read(0x6000), 0x42);
+        assert_eq!(bus.read(0x7FFF), 0x24);
+
+        std::fs::remove_file(save_path).unwrap();
+        std::fs::remove_file(rom_path).unwrap();
+    }
+}
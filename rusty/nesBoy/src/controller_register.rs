@@ -43,6 +43,34 @@ impl ControlRegister {
        }
    }
 
+   pub fn sprt_pattern_addr(&self) -> u16 {
+       if !self.contains(ControlRegister::SPRITE_PATTERN_ADDR) {
+           0
+       } else {
+           0x1000
+       }
+   }
+
+   pub fn sprite_size(&self) -> u8 {
+       if !self.contains(ControlRegister::SPRITE_SIZE) {
+           8
+       } else {
+           16
+       }
+   }
+
+   pub fn bknd_pattern_addr(&self) -> u16 {
+       if !self.contains(ControlRegister::BACKROUND_PATTERN_ADDR) {
+           0
+       } else {
+           0x1000
+       }
+   }
+
+   pub fn generate_vblank_nmi(&self) -> bool {
+       self.contains(ControlRegister::GENERATE_NMI)
+   }
+
     pub fn update(&mut self, data: u8) {
         self.bits = data;
     }
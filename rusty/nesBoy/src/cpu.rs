@@ -1,33 +1,6 @@
 use crate::bus::Bus;
 use lazy_static::lazy_static;
-// use std::collections::HashMap;
 
-// #[derive(Debug)]
-// #[allow(non_camel_case_types)]
-// pub enum AddressingMode {
-//     Immediate,
-//     Accumulator,
-//     ZeroPage,
-//     ZeroPage_X,
-//     ZeroPage_Y,
-//     Absolute,
-//     Absolute_X,
-//     Absolute_X_PageCross,
-//     Absolute_Y,
-//     Absolute_Y_PageCross,
-//     Indirect_X,
-//     Indirect_Y,
-//     Indirect_Y_PageCross,
-//     NoneAddressing,
-// }
-
-// pub struct OpsCode {
-//     pub code: u8,
-//     pub mnemonic: &'static str,
-//     pub len: u8,
-//     pub cycles: u8,
-//     pub mode: AddressingMode,
-// }
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
 pub enum FLAGS6502 {
@@ -41,6 +14,29 @@ pub enum FLAGS6502 {
     N = 1 << 7, // Negative
 }
 
+// Base cycle count per opcode; page-cross and branch-taken penalties are
+// added by `Cpu::extra_cycles`
+#[rustfmt::skip]
+pub const CYCLES: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, // 0
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 1
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6, // 2
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 3
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6, // 4
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 5
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, // 6
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 7
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 8
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5, // 9
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // A
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4, // B
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // C
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // D
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // E
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // F
+];
+
 lazy_static! {
     pub static ref NON_READABLE_ADDR: Vec<u16> = vec!(
         0x2001, 0x2002, 0x2003, 0x2004, 0x2005, 0x2006, 0x2007, 0x4016, 0x4017
@@ -56,8 +52,6 @@ pub struct Cpu {
     pub pc: u16, // Program counter
     pub p: u8,   // Status register
 
-    // 64KB memory (internal RAM)
-    pub memory: [u8; 0x10000],
     pub bus: Bus,
 }
 
@@ -70,7 +64,6 @@ impl Cpu {
             sp: 0xFD,
             pc: 0xC000,
             p: 0x24,
-            memory: [0; 0x10000],
             bus,
         }
     }
@@ -84,17 +77,105 @@ impl Cpu {
         self.p = 0x24;
     }
 
-    pub fn step(&mut self) {
+    /// Run one instruction (or take a pending NMI/IRQ), returning the CPU cycles used
+    pub fn step(&mut self) -> u16 {
+        // An OAM DMA halts the CPU while the PPU keeps running
+        let stall = self.bus.take_dma_stall();
+        if stall > 0 {
+            self.bus.tick(stall);
+            return stall;
+        }
+
+        if self.bus.poll_nmi() {
+            self.interrupt(0xFFFA);
+            self.bus.tick(7);
+            return 7;
+        }
+        if self.bus.irq() && !self.get_flag(FLAGS6502::I) {
+            self.interrupt(0xFFFE);
+            self.bus.tick(7);
+            return 7;
+        }
+
         // Fetch opcode and execute
-        println!("CPU Step: PC = {}", self.pc);
         let opcode = self.bus.read(self.pc);
-        println!("Executing opcode: {}, {}", opcode, self.pc);
         self.pc = self.pc.wrapping_add(1);
+        let cycles = CYCLES[opcode as usize] as u16 + self.extra_cycles(opcode);
         self.execute(opcode);
+
+        self.bus.tick(cycles);
+        cycles
+    }
+
+    // Penalties for the instruction about to run, PC pointing at its operand:
+    // +1 for a taken branch and another +1 if it lands on a different page,
+    // +1 when an indexed read crosses a page. Indexed stores and
+    // read-modify-write instructions always take the extra cycle, so it is
+    // already in CYCLES for them.
+    fn extra_cycles(&mut self, opcode: u8) -> u16 {
+        let page_crossed = |a: u16, b: u16| (a & 0xFF00 != b & 0xFF00) as u16;
+        match opcode {
+            // BPL BMI BVC BVS BCC BCS BNE BEQ: bits 6-7 pick the flag,
+            // bit 5 the value that takes the branch
+            0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xB0 | 0xD0 | 0xF0 => {
+                let flag = match opcode >> 6 {
+                    0 => FLAGS6502::N,
+                    1 => FLAGS6502::V,
+                    2 => FLAGS6502::C,
+                    _ => FLAGS6502::Z,
+                };
+                if self.get_flag(flag) != (opcode & 0x20 != 0) {
+                    return 0;
+                }
+                let offset = self.bus.read(self.pc) as i8;
+                let next = self.pc.wrapping_add(1);
+                1 + page_crossed(next, next.wrapping_add(offset as u16))
+            }
+            // ORA AND EOR ADC LDY LDA CMP SBC absolute,X
+            0x1D | 0x3D | 0x5D | 0x7D | 0xBC | 0xBD | 0xDD | 0xFD => {
+                let base = self.read_operand_word(self.pc);
+                page_crossed(base, base.wrapping_add(self.x as u16))
+            }
+            // ORA AND EOR ADC LDA LDX CMP SBC absolute,Y
+            0x19 | 0x39 | 0x59 | 0x79 | 0xB9 | 0xBE | 0xD9 | 0xF9 => {
+                let base = self.read_operand_word(self.pc);
+                page_crossed(base, base.wrapping_add(self.y as u16))
+            }
+            // ORA AND EOR ADC LDA CMP SBC (indirect),Y
+            0x11 | 0x31 | 0x51 | 0x71 | 0xB1 | 0xD1 | 0xF1 => {
+                let pointer = self.bus.read(self.pc);
+                let lo = self.bus.read(pointer as u16) as u16;
+                let hi = self.bus.read(pointer.wrapping_add(1) as u16) as u16;
+                let base = lo | (hi << 8);
+                page_crossed(base, base.wrapping_add(self.y as u16))
+            }
+            _ => 0,
+        }
+    }
+
+    fn read_operand_word(&mut self, addr: u16) -> u16 {
+        let lo = self.bus.read(addr) as u16;
+        let hi = self.bus.read(addr.wrapping_add(1)) as u16;
+        lo | (hi << 8)
+    }
+
+    // Hardware interrupt: push PC and status (B clear), jump through `vector`
+    fn interrupt(&mut self, vector: u16) {
+        self.bus.write(0x100 + self.sp as u16, (self.pc >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.bus
+            .write(0x100 + self.sp as u16, (self.pc & 0xFF) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        let status = (self.p & !(FLAGS6502::B as u8)) | FLAGS6502::U as u8;
+        self.bus.write(0x100 + self.sp as u16, status);
+        self.sp = self.sp.wrapping_sub(1);
+        self.set_flag(FLAGS6502::I, true);
+        let lo = self.bus.read(vector) as u16;
+        let hi = self.bus.read(vector.wrapping_add(1)) as u16;
+        self.pc = lo | (hi << 8);
     }
 
     pub fn execute(&mut self, opcode: u8) {
-        print!("op cod{} \n", opcode);
         // Execute instruction based on opcode
         match opcode {
             // BRK - Break/Software Interrupt
@@ -1176,14 +1257,6 @@ impl Cpu {
         }
     }
 
-    pub fn read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
-    }
-
-    pub fn write(&mut self, addr: u16, data: u8) {
-        self.memory[addr as usize] = data;
-    }
-
     pub fn set_flag(&mut self, flag: FLAGS6502, value: bool) {
         if value {
             self.p |= flag as u8;
@@ -1196,152 +1269,59 @@ impl Cpu {
         (self.p & (flag as u8)) != 0
     }
 
-    // pub fn trace(cpu: &mut Cpu) -> String {
-    //     let ref opscodes: HashMap<u8, &'static opscode::OpsCode> = *opscode::OPSCODES_MAP;
-    //     let ref non_readable_addr = *NON_READABLE_ADDR;
-
-    //     let code = cpu.mem_read(cpu.program_counter);
-    //     let ops = opscodes.get(&code).unwrap();
-
-    //     let begin = cpu.program_counter;
-    //     let mut hex_dump = vec![];
-    //     hex_dump.push(code);
-
-    //     let (mem_addr, stored_value) = match ops.mode {
-    //         AddressingMode::Immediate
-    //         | AddressingMode::NoneAddressing
-    //         | AddressingMode::Accumulator => (0, 0),
-    //         _ => {
-    //             let address = if ops.len == 2 {
-    //                 cpu.mem_read(begin + 1) as u16
-    //             } else {
-    //                 cpu.mem_read_u16(begin + 1)
-    //             };
-    //             let (_, addr) = ops.mode.get_absolute_addr(cpu, address);
-    //             if !non_readable_addr.contains(&addr) {
-    //                 (addr, cpu.mem_read(addr))
-    //             } else {
-    //                 (addr, 0)
-    //             }
-    //         }
-    //     };
-
-    //     let tmp = match ops.len {
-    //         1 => match ops.mode {
-    //             AddressingMode::Accumulator => format!("A "),
-    //             _ => String::from(""),
-    //         },
-    //         2 => {
-    //             let address: u8 = cpu.read(begin + 1);
-    //             // let value = cpu.mem_read(address));
-    //             hex_dump.push(address);
-
-    //             match ops.mode {
-    //                 AddressingMode::Immediate => format!("#${:02x}", address),
-    //                 AddressingMode::ZeroPage => format!("${:02x} = {:02x}", mem_addr, stored_value),
-    //                 AddressingMode::ZeroPage_X => format!(
-    //                     "${:02x},X @ {:02x} = {:02x}",
-    //                     address, mem_addr, stored_value
-    //                 ),
-    //                 AddressingMode::ZeroPage_Y => format!(
-    //                     "${:02x},Y @ {:02x} = {:02x}",
-    //                     address, mem_addr, stored_value
-    //                 ),
-    //                 AddressingMode::Indirect_X => format!(
-    //                     "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
-    //                     address,
-    //                     (address.wrapping_add(cpu.x)),
-    //                     mem_addr,
-    //                     stored_value
-    //                 ),
-    //                 AddressingMode::Indirect_Y | AddressingMode::Indirect_Y_PageCross => format!(
-    //                     "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
-    //                     address,
-    //                     (mem_addr.wrapping_sub(cpu.y as u16)),
-    //                     mem_addr,
-    //                     stored_value
-    //                 ),
-    //                 AddressingMode::NoneAddressing => {
-    //                     // assuming local jumps: BNE, BVS, etc.... todo: check ?
-    //                     let address: usize =
-    //                         (begin as usize + 2).wrapping_add((address as i8) as usize);
-    //                     format!("${:04x}", address)
-    //                 }
-
-    //                 _ => panic!(
-    //                     "unexpected addressing mode {:?} has ops-len 2. code {:02x}",
-    //                     ops.mode, ops.code
-    //                 ),
-    //             }
-    //         }
-    //         3 => {
-    //             let address_lo = cpu.read(begin + 1);
-    //             let address_hi = cpu.read(begin + 2);
-    //             hex_dump.push(address_lo);
-    //             hex_dump.push(address_hi);
-
-    //             let address = cpu.read(begin + 1);
+    /// Run until the next BRK, calling `callback` before every instruction
+    #[cfg(test)]
+    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&mut Cpu),
+    {
+        loop {
+            callback(self);
+            if self.bus.read(self.pc) == 0x00 {
+                return;
+            }
+            self.step();
+        }
+    }
+}
 
-    //             match ops.mode {
-    //                 AddressingMode::NoneAddressing => {
-    //                     if ops.code == 0x6c {
-    //                         //jmp indirect
-    //                         let jmp_addr = if address & 0x00FF == 0x00FF {
-    //                             let lo = cpu.read(address as u16);
-    //                             let hi = cpu.read(address as u16 & 0xFF00 as u16);
-    //                             (hi as u16) << 8 | (lo as u16)
-    //                         } else {
-    //                             cpu.read(address as u16) as u16
-    //                         };
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom_loader::RomLoader;
 
-    //                         // let jmp_addr = cpu.mem_read_u16(address);
-    //                         format!("(${:04x}) = {:04x}", address, jmp_addr)
-    //                     } else {
-    //                         format!("${:04x}", address)
-    //                     }
-    //                 }
-    //                 AddressingMode::Absolute => format!("${:04x} = {:02x}", mem_addr, stored_value),
-    //                 AddressingMode::Absolute_X | AddressingMode::Absolute_X_PageCross => format!(
-    //                     "${:04x},X @ {:04x} = {:02x}",
-    //                     address, mem_addr, stored_value
-    //                 ),
-    //                 AddressingMode::Absolute_Y | AddressingMode::Absolute_Y_PageCross => format!(
-    //                     "${:04x},Y @ {:04x} = {:02x}",
-    //                     address, mem_addr, stored_value
-    //                 ),
-    //                 _ => panic!(
-    //                     "unexpected addressing mode {:?} has ops-len 3. code {:02x}",
-    //                     ops.mode, ops.code
-    //                 ),
-    //             }
-    //         }
-    //         _ => String::from(""),
-    //     };
+    fn cpu_with_program(addr: u16, program: &[u8]) -> Cpu {
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 1, 1];
+        data.resize(16 + 16384 + 8192, 0);
+        let mut cpu = Cpu::new(Bus::new(RomLoader::from_bytes(&data).unwrap()).unwrap());
+        for (i, &byte) in program.iter().enumerate() {
+            cpu.bus.write(addr + i as u16, byte);
+        }
+        cpu.pc = addr;
+        cpu
+    }
 
-    //     let hex_str = hex_dump
-    //         .iter()
-    //         .map(|z| format!("{:02x}", z))
-    //         .collect::<Vec<String>>()
-    //         .join(" ");
-    //     let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
-    //         .trim()
-    //         .to_string();
+    #[test]
+    fn test_page_cross_and_branch_cycles() {
+        let mut cpu = cpu_with_program(
+            0x0200,
+            &[
+                0xA2, 0x01, // LDX #$01
+                0xBD, 0xFF, 0x02, // LDA $02FF,X: crosses into $0300
+                0xBD, 0x00, 0x02, // LDA $0200,X
+                0x9D, 0xFF, 0x02, // STA $02FF,X: always 5
+                0xA9, 0x00, // LDA #$00
+                0xF0, 0x00, // BEQ +0: taken
+                0xD0, 0x00, // BNE: not taken
+            ],
+        );
+        let cycles: Vec<u16> = (0..7).map(|_| cpu.step()).collect();
+        assert_eq!(cycles, [2, 5, 4, 5, 2, 3, 2]);
 
-    //     // let bus_trace = cpu.bus.trace();
-    //     format!(
-    //         // "{:47} A:{:02x} X:{:02x} Y:{:02x} SP:{:02x} FL:{:08b}",
-    //         "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} PPU:{:3},{:3} CYC:{}",
-    //         // "{:30}(a:{:x}, x:{:x}, y:{:x}, sp:{:x}, fl:{:x})",
-    //         asm_str,
-    //         cpu.a,
-    //         cpu.x,
-    //         cpu.y,
-    //         cpu.f,
-    //         cpu.stack_pointer,
-    //         // bus_trace.ppu_cycles,
-    //         bus_trace.ppu_scanline,
-    //         bus_trace.cpu_cycles
-    //     )
-    //     .to_ascii_uppercase()
-    // }
+        // Taken branch onto the next page
+        let mut cpu = cpu_with_program(0x02FC, &[0xB0, 0x10]); // BCS +$10
+        cpu.set_flag(FLAGS6502::C, true);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.pc, 0x030E);
+    }
 }
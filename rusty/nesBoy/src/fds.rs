@@ -0,0 +1,794 @@
+// Famicom Disk System: RAM adapter registers, disk drive and expansion audio
+// https://www.nesdev.org/wiki/Family_Computer_Disk_System
+// https://www.nesdev.org/wiki/FDS_audio
+
+use std::io;
+use std::path::Path;
+
+use crate::battery::BatteryRam;
+use crate::ppu::Mirroring;
+
+pub const FDS_SIDE_SIZE: usize = 65500; // one side in .fds (fwNES) layout
+pub const FDS_BIOS_SIZE: usize = 8192;
+pub const FDS_HEADER_MAGIC: &[u8] = b"FDS\x1A";
+pub const FDS_DISK_MAGIC: &[u8] = b"\x01*NINTENDO-HVC*";
+
+// Sides are expanded to the bitstream the drive actually sees: a leading gap,
+// then each block as gap-end marker + data + CRC, followed by a gap
+const SIDE_CAPACITY: usize = 68000;
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const GAP_END_MARKER: u8 = 0x80;
+
+// Drive timing in CPU cycles (~96.4 kHz bit rate)
+const BYTE_DELAY: u32 = 150;
+const REWIND_DELAY: u32 = 50000;
+// How long a side stays ejected when flipping, so the BIOS notices the swap
+const SIDE_SWAP_DELAY: u32 = 1_000_000;
+
+pub struct Fds {
+    bios: Vec<u8>,
+    ram: Vec<u8>,  // 32KB at $6000-$DFFF
+    disk: Vec<u8>, // all sides, gapped, SIDE_CAPACITY bytes each
+    sides: usize,
+    side: Option<usize>,
+    pending_side: Option<usize>,
+    swap_delay: u32,
+    save: Option<BatteryRam>,
+
+    // $4020-$4023
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_repeat: bool,
+    irq_enabled: bool,
+    disk_reg_enabled: bool,
+    sound_reg_enabled: bool,
+    timer_irq: bool,
+
+    // $4024-$4025
+    write_data: u8,
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    horizontal_mirroring: bool,
+    crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+    ext_output: u8,
+
+    // Drive state
+    disk_irq: bool,
+    read_data: u8,
+    transfer_complete: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    position: usize,
+    delay: u32,
+
+    pub audio: FdsAudio,
+}
+
+impl Fds {
+    /// Build the drive from a .fds image (with or without the 16-byte header)
+    pub fn new(image: &[u8]) -> Self {
+        let sides = image.len() / FDS_SIDE_SIZE;
+        let mut disk = Vec::with_capacity(sides * SIDE_CAPACITY);
+        for side in image.chunks_exact(FDS_SIDE_SIZE) {
+            disk.extend(add_gaps(side));
+        }
+
+        Fds {
+            bios: Vec::new(),
+            ram: vec![0; 0x8000],
+            disk,
+            sides,
+            side: if sides > 0 { Some(0) } else { None },
+            pending_side: None,
+            swap_delay: 0,
+            save: None,
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_repeat: false,
+            irq_enabled: false,
+            disk_reg_enabled: false,
+            sound_reg_enabled: false,
+            timer_irq: false,
+            write_data: 0,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            horizontal_mirroring: false,
+            crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            ext_output: 0,
+            disk_irq: false,
+            read_data: 0,
+            transfer_complete: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            position: 0,
+            delay: 0,
+            audio: FdsAudio::new(),
+        }
+    }
+
+    pub fn load_bios(&mut self, bios: Vec<u8>) {
+        self.bios = bios;
+    }
+
+    /// Disk writes go to game.sav instead of the original image. The save
+    /// holds every side in .fds layout (65500 bytes each, no header), so it
+    /// can be loaded as a disk image itself.
+    pub fn attach_save(&mut self, rom_path: &str) -> io::Result<&Path> {
+        let save = BatteryRam::for_rom(rom_path);
+        let mut image = self.image();
+        save.load(&mut image)?;
+        self.disk = image
+            .chunks_exact(FDS_SIDE_SIZE)
+            .flat_map(add_gaps)
+            .collect();
+        Ok(self.save.insert(save).path())
+    }
+
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        let image = self.image();
+        match &mut self.save {
+            Some(save) => save.flush(&image),
+            None => Ok(()),
+        }
+    }
+
+    // All sides back in .fds layout
+    fn image(&self) -> Vec<u8> {
+        self.disk
+            .chunks_exact(SIDE_CAPACITY)
+            .flat_map(remove_gaps)
+            .collect()
+    }
+
+    /// Eject the disk and insert the next side (or flip back to side A),
+    /// returning the index of the side going in
+    pub fn switch_side(&mut self) -> Option<usize> {
+        if self.sides == 0 {
+            return None;
+        }
+        let next = match self.side.or(self.pending_side) {
+            Some(side) => (side + 1) % self.sides,
+            None => 0,
+        };
+        self.eject();
+        self.pending_side = Some(next);
+        self.swap_delay = SIDE_SWAP_DELAY;
+        Some(next)
+    }
+
+    pub fn eject(&mut self) {
+        self.side = None;
+        self.pending_side = None;
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        if self.horizontal_mirroring {
+            Mirroring::HORIZONTAL
+        } else {
+            Mirroring::VERTICAL
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    /// CPU-side reads the RAM adapter decodes, None for everything else
+    pub fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030 if self.disk_reg_enabled => {
+                let mut value = 0x80; // disk r/w enabled
+                if self.timer_irq {
+                    value |= 0x01;
+                }
+                if self.transfer_complete {
+                    value |= 0x02;
+                }
+                if self.end_of_head {
+                    value |= 0x40;
+                }
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                Some(value)
+            }
+            0x4031 if self.disk_reg_enabled => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                Some(self.read_data)
+            }
+            0x4032 if self.disk_reg_enabled => {
+                let inserted = self.side.is_some();
+                let mut value = 0x40;
+                if !inserted {
+                    value |= 0x01 | 0x04; // no disk, write protected
+                }
+                if !inserted || !self.scanning {
+                    value |= 0x02;
+                }
+                Some(value)
+            }
+            0x4033 if self.disk_reg_enabled => Some(0x80), // battery good
+            0x4040..=0x4097 if self.sound_reg_enabled => Some(self.audio.read(addr)),
+            0x6000..=0xDFFF => Some(self.ram[(addr - 0x6000) as usize]),
+            0xE000..=0xFFFF => Some(
+                self.bios
+                    .get((addr - 0xE000) as usize)
+                    .copied()
+                    .unwrap_or(0),
+            ),
+            _ => None,
+        }
+    }
+
+    /// CPU-side writes, returns false if the address isn't ours
+    pub fn write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x4020 => self.irq_reload = (self.irq_reload & 0xFF00) | data as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00FF) | ((data as u16) << 8),
+            0x4022 => {
+                if self.disk_reg_enabled {
+                    self.irq_repeat = data & 0x01 != 0;
+                    self.irq_enabled = data & 0x02 != 0;
+                    if self.irq_enabled {
+                        self.irq_counter = self.irq_reload;
+                    } else {
+                        self.timer_irq = false;
+                    }
+                }
+            }
+            0x4023 => {
+                self.disk_reg_enabled = data & 0x01 != 0;
+                self.sound_reg_enabled = data & 0x02 != 0;
+                if !self.disk_reg_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024 if self.disk_reg_enabled => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 if self.disk_reg_enabled => {
+                self.disk_irq = false;
+                self.motor_on = data & 0x01 != 0;
+                self.reset_transfer = data & 0x02 != 0;
+                self.read_mode = data & 0x04 != 0;
+                self.horizontal_mirroring = data & 0x08 != 0;
+                self.crc_control = data & 0x10 != 0;
+                self.disk_ready = data & 0x40 != 0;
+                self.disk_irq_enabled = data & 0x80 != 0;
+            }
+            0x4026 if self.disk_reg_enabled => self.ext_output = data,
+            0x4024..=0x4026 => {}
+            0x4040..=0x408A if self.sound_reg_enabled => self.audio.write(addr, data),
+            0x4040..=0x408A => {}
+            0x6000..=0xDFFF => self.ram[(addr - 0x6000) as usize] = data,
+            0xE000..=0xFFFF => {} // BIOS ROM
+            _ => return false,
+        }
+        true
+    }
+
+    /// One CPU cycle of the timer IRQ, the drive and the sound channel
+    pub fn clock(&mut self) {
+        self.clock_timer();
+        self.clock_drive();
+        self.audio.clock();
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.irq_enabled || !self.disk_reg_enabled {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.timer_irq = true;
+            self.irq_counter = self.irq_reload;
+            if !self.irq_repeat {
+                self.irq_enabled = false;
+            }
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        if self.pending_side.is_some() {
+            self.swap_delay = self.swap_delay.saturating_sub(1);
+            if self.swap_delay == 0 {
+                self.side = self.pending_side.take();
+            }
+        }
+
+        let Some(side) = self.side else {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        };
+        if !self.motor_on {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        }
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        // Head is parked at the outer edge: rewind to the start of the side
+        if self.end_of_head {
+            self.delay = REWIND_DELAY;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let index = side * SIDE_CAPACITY + self.position;
+        let mut need_irq = self.disk_irq_enabled;
+
+        if self.read_mode {
+            let data = self.disk[index];
+            if !self.disk_ready {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // The gap-end marker itself is swallowed
+                self.gap_ended = true;
+                need_irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                if need_irq {
+                    self.disk_irq = true;
+                }
+            }
+        } else {
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                if need_irq {
+                    self.disk_irq = true;
+                }
+            }
+            if !self.disk_ready {
+                data = 0;
+            }
+            self.disk[index] = data;
+            if let Some(save) = &mut self.save {
+                save.mark_dirty();
+            }
+            self.gap_ended = false;
+        }
+
+        self.position += 1;
+        if self.position >= SIDE_CAPACITY {
+            self.motor_on = false;
+            self.end_of_head = true;
+        } else {
+            self.delay = BYTE_DELAY;
+        }
+    }
+}
+
+// Turn one 65500-byte fwNES side into the gapped bitstream the drive reads
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut out = vec![0u8; LEAD_IN_GAP];
+    let mut pos = 0;
+
+    while pos < side.len() {
+        let len = match side[pos] {
+            1 => 56, // disk info
+            2 => 2,  // file amount
+            3 => 16, // file header
+            // file data, size comes from the preceding file header
+            4 if pos >= 3 => 1 + (side[pos - 3] as usize | (side[pos - 2] as usize) << 8),
+            _ => break,
+        };
+        let end = (pos + len).min(side.len());
+
+        out.push(GAP_END_MARKER);
+        out.extend_from_slice(&side[pos..end]);
+        out.extend([0, 0]); // CRC, the drive never reports CRC errors
+        out.extend(std::iter::repeat_n(0, BLOCK_GAP));
+        pos = end;
+    }
+
+    out.resize(SIDE_CAPACITY, 0);
+    out
+}
+
+// Inverse of add_gaps: pull the blocks back out of a (possibly rewritten)
+// bitstream and pad them to a 65500-byte side
+fn remove_gaps(gapped: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(FDS_SIDE_SIZE);
+    let mut pos = 0;
+
+    // Skip the gap and its end marker, then copy the block and drop its CRC
+    while let Some(marker) = gapped
+        .get(pos..)
+        .and_then(|rest| rest.iter().position(|&b| b != 0))
+    {
+        pos += marker + 1;
+        let len = match gapped.get(pos) {
+            Some(1) => 56,
+            Some(2) => 2,
+            Some(3) => 16,
+            Some(4) if out.len() >= 3 => {
+                1 + (out[out.len() - 3] as usize | (out[out.len() - 2] as usize) << 8)
+            }
+            _ => break,
+        };
+        let end = (pos + len).min(gapped.len());
+        out.extend_from_slice(&gapped[pos..end]);
+        pos = end + 2;
+    }
+
+    out.resize(FDS_SIDE_SIZE, 0);
+    out
+}
+
+/// Strip the optional 16-byte fwNES header, returning the raw sides
+pub fn strip_header(image: &[u8]) -> &[u8] {
+    if image.starts_with(FDS_HEADER_MAGIC) {
+        &image[16..]
+    } else {
+        image
+    }
+}
+
+// Modulation table steps, index 4 resets the counter
+const MOD_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+// $4089 master volume: 2/2, 2/3, 2/4, 2/5
+const MASTER_VOLUME: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+
+struct Envelope {
+    speed: u8,
+    gain: u8,
+    increase: bool,
+    disabled: bool,
+    timer: u32,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            speed: 0,
+            gain: 0,
+            increase: false,
+            disabled: true,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, data: u8, master_speed: u8) {
+        self.speed = data & 0x3F;
+        self.increase = data & 0x40 != 0;
+        self.disabled = data & 0x80 != 0;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.reset_timer(master_speed);
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.reset_timer(master_speed);
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+pub struct FdsAudio {
+    wave_table: [u8; 64],
+    wave_write: bool,
+    master_volume: u8,
+
+    frequency: u16,
+    wave_halt: bool,
+    envelopes_halt: bool,
+    wave_accumulator: u32,
+
+    volume: Envelope,
+    mod_envelope: Envelope,
+    master_env_speed: u8,
+
+    mod_table: [u8; 64],
+    mod_position: usize,
+    mod_counter: i8, // 7-bit signed
+    mod_frequency: u16,
+    mod_halt: bool,
+    mod_accumulator: u32,
+
+    output: u8,
+}
+
+impl FdsAudio {
+    fn new() -> Self {
+        FdsAudio {
+            wave_table: [0; 64],
+            wave_write: false,
+            master_volume: 0,
+            frequency: 0,
+            wave_halt: true,
+            envelopes_halt: false,
+            wave_accumulator: 0,
+            volume: Envelope::new(),
+            mod_envelope: Envelope::new(),
+            master_env_speed: 0xE8,
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_counter: 0,
+            mod_frequency: 0,
+            mod_halt: true,
+            mod_accumulator: 0,
+            output: 0,
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x407F => self.wave_table[(addr - 0x4040) as usize] | 0x40,
+            0x4090 => self.volume.gain | 0x40,
+            0x4092 => self.mod_envelope.gain | 0x40,
+            _ => 0x40,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write => {
+                self.wave_table[(addr - 0x4040) as usize] = data & 0x3F;
+            }
+            0x4080 => self.volume.write(data, self.master_env_speed),
+            0x4082 => self.frequency = (self.frequency & 0x0F00) | data as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.wave_halt = data & 0x80 != 0;
+                self.envelopes_halt = data & 0x40 != 0;
+                if self.wave_halt {
+                    self.wave_accumulator = 0;
+                }
+                if self.envelopes_halt {
+                    self.volume.reset_timer(self.master_env_speed);
+                    self.mod_envelope.reset_timer(self.master_env_speed);
+                }
+            }
+            0x4084 => self.mod_envelope.write(data, self.master_env_speed),
+            0x4085 => {
+                // sign-extend the 7-bit counter
+                self.mod_counter = ((data & 0x7F) << 1) as i8 >> 1;
+            }
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | data as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.mod_halt = data & 0x80 != 0;
+                if self.mod_halt {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // Table is only writable while halted, each write fills two entries
+            0x4088 if self.mod_halt => {
+                self.mod_table[self.mod_position] = data & 0x07;
+                self.mod_table[(self.mod_position + 1) & 0x3F] = data & 0x07;
+                self.mod_position = (self.mod_position + 2) & 0x3F;
+            }
+            0x4089 => {
+                self.wave_write = data & 0x80 != 0;
+                self.master_volume = data & 0x03;
+            }
+            0x408A => self.master_env_speed = data,
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.envelopes_halt && !self.wave_halt && self.master_env_speed != 0 {
+            self.volume.clock(self.master_env_speed);
+            self.mod_envelope.clock(self.master_env_speed);
+        }
+
+        if !self.mod_halt && self.mod_frequency > 0 {
+            self.mod_accumulator += self.mod_frequency as u32;
+            if self.mod_accumulator >= 0x10000 {
+                self.mod_accumulator &= 0xFFFF;
+                let step = self.mod_table[self.mod_position];
+                self.mod_position = (self.mod_position + 1) & 0x3F;
+                self.mod_counter = if step == 4 {
+                    0
+                } else {
+                    // wraps within 7 bits
+                    ((self.mod_counter.wrapping_add(MOD_STEPS[step as usize]) as u8) << 1) as i8
+                        >> 1
+                };
+            }
+        }
+
+        // The wave output is frozen while the CPU can write the table
+        if self.wave_halt || self.wave_write {
+            return;
+        }
+        let pitch = self.modulated_pitch();
+        self.wave_accumulator = (self.wave_accumulator + pitch) & 0x3F_FFFF;
+        let position = (self.wave_accumulator >> 16) as usize & 0x3F;
+        self.output = self.wave_table[position];
+    }
+
+    // Pitch after applying the modulator, per the nesdev reference algorithm
+    fn modulated_pitch(&self) -> u32 {
+        let pitch = self.frequency as i32;
+        let mut temp = self.mod_counter as i32 * self.mod_envelope.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= pitch;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        (pitch + temp).max(0) as u32
+    }
+
+    /// Current channel level in 0.0..=1.0 for the mixer
+    pub fn output(&self) -> f32 {
+        let gain = self.volume.gain.min(32) as f32;
+        self.output as f32 * gain / (63.0 * 32.0) * MASTER_VOLUME[self.master_volume as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn side() -> Vec<u8> {
+        let mut side = vec![0u8; FDS_SIDE_SIZE];
+        side[0] = 1;
+        side[1..15].copy_from_slice(b"*NINTENDO-HVC*");
+        side[56] = 2;
+        side[57] = 1; // one file
+        side[58] = 3;
+        side[58 + 13] = 4; // file size 4
+        side[74] = 4;
+        side[75..79].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        side
+    }
+
+    #[test]
+    fn test_add_gaps() {
+        let gapped = add_gaps(&side());
+        assert_eq!(gapped.len(), SIDE_CAPACITY);
+        assert!(gapped[..LEAD_IN_GAP].iter().all(|&b| b == 0));
+        assert_eq!(gapped[LEAD_IN_GAP], GAP_END_MARKER);
+        assert_eq!(
+            &gapped[LEAD_IN_GAP + 1..LEAD_IN_GAP + 15],
+            b"\x01*NINTENDO-HVC"
+        );
+
+        // last block: marker, id 4, four data bytes
+        let data = gapped
+            .windows(4)
+            .position(|w| w == [0xDE, 0xAD, 0xBE, 0xEF])
+            .unwrap();
+        assert_eq!(gapped[data - 2..data], [GAP_END_MARKER, 4]);
+    }
+
+    #[test]
+    fn test_save_round_trip() {
+        let rom_path = std::env::temp_dir().join(format!("nesboy-{}-disk.fds", std::process::id()));
+        let rom_path = rom_path.to_str().unwrap();
+        let mut fds = Fds::new(&side());
+        let save_path = fds.attach_save(rom_path).unwrap().to_path_buf();
+
+        // Rewrite the file data in place, as the drive would
+        let data = fds
+            .disk
+            .windows(4)
+            .position(|w| w == [0xDE, 0xAD, 0xBE, 0xEF])
+            .unwrap();
+        fds.disk[data] = 0x42;
+        fds.save.as_mut().unwrap().mark_dirty();
+        fds.flush_save().unwrap();
+
+        let mut expected = side();
+        expected[75] = 0x42;
+        assert_eq!(std::fs::read(&save_path).unwrap(), expected);
+
+        let mut reloaded = Fds::new(&side());
+        reloaded.attach_save(rom_path).unwrap();
+        assert!(reloaded.disk == fds.disk);
+        std::fs::remove_file(save_path).unwrap();
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = Fds::new(&side());
+        fds.write(0x4023, 0x01);
+        fds.write(0x4020, 0x02);
+        fds.write(0x4021, 0x00);
+        fds.write(0x4022, 0x03); // enabled, repeat
+
+        for _ in 0..2 {
+            fds.clock_timer();
+            assert!(!fds.irq());
+        }
+        fds.clock_timer();
+        assert!(fds.irq());
+        assert_eq!(fds.read(0x4030).unwrap() & 0x01, 0x01);
+        assert!(!fds.irq());
+    }
+
+    #[test]
+    fn test_read_first_block() {
+        let mut fds = Fds::new(&side());
+        fds.write(0x4023, 0x01);
+        // motor on, read mode, ready, byte IRQs
+        fds.write(0x4025, 0x80 | 0x40 | 0x04 | 0x01);
+
+        let mut bytes = vec![];
+        for _ in 0..(LEAD_IN_GAP as u32 + 20) * (BYTE_DELAY + 1) + REWIND_DELAY + 2 {
+            fds.clock_drive();
+            if fds.disk_irq {
+                bytes.push(fds.read(0x4031).unwrap());
+            }
+        }
+        assert_eq!(&bytes[..15], b"\x01*NINTENDO-HVC*");
+    }
+
+    #[test]
+    fn test_wavetable_output() {
+        let mut fds = Fds::new(&side());
+        fds.write(0x4023, 0x03); // disk and sound registers on
+        fds.write(0x4089, 0x80); // wave table writable
+        for addr in 0x4040..0x4060 {
+            fds.write(addr, 0x3F); // first half high, second half low
+        }
+        assert_eq!(fds.read(0x4040), Some(0x7F));
+        fds.write(0x4089, 0x00); // full master volume
+        fds.write(0x4080, 0x80 | 32); // envelope off, gain 32
+        fds.write(0x4082, 0x00);
+        fds.write(0x4083, 0x01); // one table step every 256 cycles
+
+        fds.clock();
+        assert_eq!(fds.audio.output(), 1.0);
+        for _ in 0..32 * 256 {
+            fds.clock();
+        }
+        assert_eq!(fds.audio.output(), 0.0);
+    }
+}
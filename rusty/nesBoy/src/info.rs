@@ -0,0 +1,363 @@
+// `nesBoy info [--json] ROM...`: header, hash and database report for ROM files
+
+use crate::archive;
+use crate::fds::FDS_SIDE_SIZE;
+use crate::nes_header::{ConsoleType, HeaderFormat, NesHeader, Timing};
+use crate::ppu::Mirroring;
+use crate::rom_loader::{RomError, RomLoader, TRAINER_SIZE};
+
+pub fn mapper_name(mapper: u16) -> &'static str {
+    match mapper {
+        0 => "NROM",
+        1 => "MMC1 (SxROM)",
+        2 => "UxROM",
+        3 => "CNROM",
+        4 => "MMC3 (TxROM)",
+        5 => "MMC5 (ExROM)",
+        7 => "AxROM",
+        9 => "MMC2 (PxROM)",
+        10 => "MMC4 (FxROM)",
+        11 => "Color Dreams",
+        13 => "CPROM",
+        16 => "Bandai FCG",
+        19 => "Namco 163",
+        20 => "Famicom Disk System",
+        21 | 22 | 23 | 25 => "Konami VRC2/VRC4",
+        24 | 26 => "Konami VRC6",
+        34 => "BNROM / NINA-001",
+        66 => "GxROM",
+        69 => "Sunsoft FME-7",
+        71 => "Camerica",
+        85 => "Konami VRC7",
+        118 => "TxSROM",
+        119 => "TQROM",
+        206 => "Namco 118 (DxROM)",
+        _ => "Unknown",
+    }
+}
+
+pub struct RomInfo {
+    path: String,
+    rom: RomLoader,
+    issues: Vec<String>,
+}
+
+impl RomInfo {
+    /// Inspect a ROM as dumped: archives are unpacked but no patch is applied,
+    /// so the hashes are the ones the database knows
+    pub fn load(path: &str) -> Result<Self, RomError> {
+        let data = archive::read_rom_image(path, None)?;
+        let rom = RomLoader::from_bytes(&data)?;
+        let issues = Self::find_issues(&rom, data.len());
+        Ok(RomInfo {
+            path: path.to_string(),
+            rom,
+            issues,
+        })
+    }
+
+    fn find_issues(rom: &RomLoader, file_len: usize) -> Vec<String> {
+        let header = rom.header();
+        let mut issues = Vec::new();
+        if !matches!(header.format, HeaderFormat::INes | HeaderFormat::Nes20) {
+            return issues;
+        }
+
+        // iNES 1.0 leaves bytes 12-15 zero, old dumpers signed their name there
+        if header.format == HeaderFormat::INes && NesHeader::has_dumper_garbage(&header.raw) {
+            let text: String = header.raw[7..16]
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                .collect();
+            issues.push(format!(
+                "header bytes 7-15 contain garbage ({:?}), flags 7-9 ignored",
+                text
+            ));
+        }
+
+        let trainer = if header.has_trainer { TRAINER_SIZE } else { 0 };
+        let expected = NesHeader::SIZE + trainer + header.prg_rom_size + header.chr_rom_size;
+        if file_len != expected && header.misc_roms == 0 {
+            issues.push(format!(
+                "file is {} bytes but the header accounts for {} ({} trailing)",
+                file_len,
+                expected,
+                file_len - expected
+            ));
+        }
+
+        // Compare what the header says against the database entry
+        if let (Some(entry), Ok(original)) = (rom.db_entry(), NesHeader::parse(&header.raw)) {
+            if original.mapper != entry.mapper {
+                issues.push(format!(
+                    "header says mapper {}, database says {}",
+                    original.mapper, entry.mapper
+                ));
+            }
+            if original.has_battery != entry.battery {
+                issues.push(format!(
+                    "header says battery {}, database says {}",
+                    yes_no(original.has_battery),
+                    yes_no(entry.battery)
+                ));
+            }
+            if original.mirroring() != header.mirroring() {
+                issues.push(format!(
+                    "header says {} mirroring, database says {}",
+                    mirroring_name(original.mirroring()),
+                    mirroring_name(header.mirroring())
+                ));
+            }
+        }
+
+        issues
+    }
+
+    pub fn print_text(&self) {
+        let rom = &self.rom;
+        let header = rom.header();
+        let hashes = rom.hashes();
+
+        println!("{}:", self.path);
+        match rom.db_entry() {
+            Some(entry) => {
+                println!("  Title: {}", entry.title);
+                println!("  Region: {}", entry.region());
+            }
+            None => match rom.title() {
+                Some(title) => println!("  Title: {} (not in database)", title),
+                None => println!("  Title: (not in database)"),
+            },
+        }
+        println!("  CRC32: {:08X}", hashes.crc32);
+        println!("  SHA-1: {}", hashes.sha1_hex());
+        println!("  Header Format: {}", format_name(header.format));
+        if let Some(board) = rom.board() {
+            println!("  Board: {}", board);
+        }
+        if let Some(image) = rom.fds_image() {
+            println!("  Disk Sides: {}", image.len() / FDS_SIDE_SIZE);
+        }
+        println!("  Mapper: {} ({})", rom.mapper(), mapper_name(rom.mapper()));
+        if header.is_nes20() {
+            println!("  Submapper: {}", rom.submapper());
+        }
+        println!(
+            "  PRG-ROM: {} KB ({} x 16KB banks)",
+            header.prg_rom_size / 1024,
+            header.prg_rom_size / 16384
+        );
+        println!(
+            "  CHR-ROM: {} KB ({} x 8KB banks)",
+            header.chr_rom_size / 1024,
+            header.chr_rom_size / 8192
+        );
+        println!("  Mirroring: {}", mirroring_name(rom.mirroring()));
+        println!("  Battery-backed RAM: {}", yes_no(rom.has_battery()));
+        println!("  Trainer Present: {}", yes_no(rom.has_trainer()));
+        println!("  PRG-RAM Size: {} KB", header.prg_ram_size / 1024);
+        println!("  PRG-NVRAM Size: {} KB", header.prg_nvram_size / 1024);
+        println!("  CHR-RAM Size: {} KB", header.chr_ram_size / 1024);
+        if header.chr_nvram_size > 0 {
+            println!("  CHR-NVRAM Size: {} KB", header.chr_nvram_size / 1024);
+        }
+        println!("  Timing: {}", timing_name(header.timing));
+        match header.console_type {
+            ConsoleType::Nes => {}
+            console_type => println!("  Console Type: {:?}", console_type),
+        }
+        if header.default_expansion_device != 0 {
+            println!("  Expansion Device: {}", header.default_expansion_device);
+        }
+        if self.issues.is_empty() {
+            println!("  Issues: none");
+        } else {
+            println!("  Issues:");
+            for issue in &self.issues {
+                println!("    - {}", issue);
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let rom = &self.rom;
+        let header = rom.header();
+        let entry = rom.db_entry();
+        let opt = |s: Option<&str>| s.map(json_string).unwrap_or("null".to_string());
+
+        let fields = [
+            ("path", json_string(&self.path)),
+            (
+                "title",
+                opt(entry.map(|e| e.title.as_str()).or(rom.title())),
+            ),
+            ("region", opt(entry.map(|e| e.region()))),
+            ("in_database", entry.is_some().to_string()),
+            ("crc32", json_string(&format!("{:08X}", rom.hashes().crc32))),
+            ("sha1", json_string(&rom.hashes().sha1_hex())),
+            ("format", json_string(format_name(header.format))),
+            ("board", opt(rom.board())),
+            ("mapper", rom.mapper().to_string()),
+            ("mapper_name", json_string(mapper_name(rom.mapper()))),
+            ("submapper", rom.submapper().to_string()),
+            ("prg_rom_size", header.prg_rom_size.to_string()),
+            ("prg_banks", (header.prg_rom_size / 16384).to_string()),
+            ("chr_rom_size", header.chr_rom_size.to_string()),
+            ("chr_banks", (header.chr_rom_size / 8192).to_string()),
+            ("mirroring", json_string(mirroring_name(rom.mirroring()))),
+            ("battery", rom.has_battery().to_string()),
+            ("trainer", rom.has_trainer().to_string()),
+            ("prg_ram_size", header.prg_ram_size.to_string()),
+            ("prg_nvram_size", header.prg_nvram_size.to_string()),
+            ("chr_ram_size", header.chr_ram_size.to_string()),
+            ("chr_nvram_size", header.chr_nvram_size.to_string()),
+            ("timing", json_string(timing_name(header.timing))),
+            ("header_corrected", rom.header_corrected().to_string()),
+            (
+                "issues",
+                format!(
+                    "[{}]",
+                    self.issues
+                        .iter()
+                        .map(|i| json_string(i))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            ),
+        ];
+
+        let body: Vec<String> = fields
+            .iter()
+            .map(|(key, value)| format!("\"{}\": {}", key, value))
+            .collect();
+        format!("{{{}}}", body.join(", "))
+    }
+}
+
+/// Entry point for `nesBoy info`, returns the process exit code
+pub fn run(args: impl Iterator<Item = String>) -> i32 {
+    let mut json = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        eprintln!("usage: nesBoy info [--json] ROM...");
+        return 2;
+    }
+
+    let mut status = 0;
+    let mut reports = Vec::new();
+    for path in &paths {
+        match RomInfo::load(path) {
+            Ok(info) if json => reports.push(info.to_json()),
+            Ok(info) => info.print_text(),
+            Err(e) => {
+                status = 1;
+                if json {
+                    reports.push(format!(
+                        "{{\"path\": {}, \"error\": {}}}",
+                        json_string(path),
+                        json_string(&e.to_string())
+                    ));
+                } else {
+                    eprintln!("{}: {}", path, e);
+                }
+            }
+        }
+    }
+    if json {
+        println!("[{}]", reports.join(",\n "));
+    }
+    status
+}
+
+fn format_name(format: HeaderFormat) -> &'static str {
+    match format {
+        HeaderFormat::INes => "iNES",
+        HeaderFormat::Nes20 => "NES 2.0",
+        HeaderFormat::Fds => "FDS disk image",
+        HeaderFormat::Unif => "UNIF",
+    }
+}
+
+fn timing_name(timing: Timing) -> &'static str {
+    match timing {
+        Timing::Ntsc => "NTSC",
+        Timing::Pal => "PAL",
+        Timing::MultiRegion => "Multi-region",
+        Timing::Dendy => "Dendy",
+    }
+}
+
+fn mirroring_name(mirroring: Mirroring) -> &'static str {
+    match mirroring {
+        Mirroring::HORIZONTAL => "Horizontal",
+        Mirroring::VERTICAL => "Vertical",
+        Mirroring::SINGLE_SCREEN_A => "Single-screen A",
+        Mirroring::SINGLE_SCREEN_B => "Single-screen B",
+        Mirroring::FOUR_SCREEN => "Four-screen",
+    }
+}
+
+fn yes_no(b: bool) -> &'static str {
+    if b { "Yes" } else { "No" }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_detects_header_garbage_and_trailing_data() {
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 1, 0, 0x10];
+        data.extend(b"DiskDude!");
+        data.extend(vec![0xEA; 16384]);
+        data.extend(vec![0; 128]);
+        let rom = RomLoader::from_bytes(&data).unwrap();
+        assert_eq!(rom.mapper(), 1);
+
+        let issues = RomInfo::find_issues(&rom, data.len());
+        assert_eq!(issues.len(), 2);
+        assert!(issues[0].contains("DiskDude!"));
+        assert!(issues[1].contains("128 trailing"));
+
+        // Byte 11 alone doesn't trip the check, the loader trusts flags7 there
+        let mut data = vec![
+            b'N', b'E', b'S', 0x1A, 1, 0, 0x10, 0x10, 0, 0, 0, 0x01, 0, 0, 0, 0,
+        ];
+        data.resize(16 + 16384, 0xEA);
+        let rom = RomLoader::from_bytes(&data).unwrap();
+        assert_eq!(rom.mapper(), 0x11);
+        assert!(RomInfo::find_issues(&rom, data.len()).is_empty());
+    }
+
+    #[test]
+    fn test_json_output() {
+        let info = RomInfo::load("ff.nes").unwrap();
+        let json = info.to_json();
+        assert!(json.starts_with("{\"path\": \"ff.nes\", \"title\": \"Final Fantasy\""));
+        assert!(json.contains("\"mapper_name\": \"MMC1 (SxROM)\""));
+        assert!(json.contains("\"header says battery No, database says Yes\""));
+        assert_eq!(json_string("a\"b\\"), "\"a\\\"b\\\\\"");
+    }
+}
@@ -0,0 +1,159 @@
+// Internal VRAM address / scroll register, used for both v (current
+// address) and t (temporary address, the top-left scroll position)
+// https://www.nesdev.org/wiki/PPU_scrolling
+//
+// yyy NN YYYYY XXXXX
+// ||| || ||||| +++++-- coarse X scroll
+// ||| || +++++-------- coarse Y scroll
+// ||| ++-------------- nametable select
+// +++----------------- fine Y scroll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopyRegister {
+    value: u16,
+}
+
+const COARSE_X: u16 = 0x001F;
+const COARSE_Y: u16 = 0x03E0;
+const NAMETABLE_X: u16 = 0x0400;
+const NAMETABLE_Y: u16 = 0x0800;
+const NAMETABLE: u16 = NAMETABLE_X | NAMETABLE_Y;
+const FINE_Y: u16 = 0x7000;
+
+impl LoopyRegister {
+    pub fn new() -> Self {
+        LoopyRegister { value: 0 }
+    }
+
+    pub fn get(&self) -> u16 {
+        self.value
+    }
+
+    #[cfg(test)]
+    pub fn set(&mut self, data: u16) {
+        self.value = data & 0x7FFF;
+    }
+
+    pub fn coarse_x(&self) -> u16 {
+        self.value & COARSE_X
+    }
+
+    pub fn coarse_y(&self) -> u16 {
+        (self.value & COARSE_Y) >> 5
+    }
+
+    pub fn fine_y(&self) -> u16 {
+        (self.value & FINE_Y) >> 12
+    }
+
+    pub fn set_nametable(&mut self, data: u8) {
+        self.value = (self.value & !NAMETABLE) | ((data as u16 & 0x03) << 10);
+    }
+
+    pub fn set_coarse_x(&mut self, data: u8) {
+        self.value = (self.value & !COARSE_X) | (data as u16 & 0x1F);
+    }
+
+    pub fn set_coarse_y(&mut self, data: u8) {
+        self.value = (self.value & !COARSE_Y) | ((data as u16 & 0x1F) << 5);
+    }
+
+    pub fn set_fine_y(&mut self, data: u8) {
+        self.value = (self.value & !FINE_Y) | ((data as u16 & 0x07) << 12);
+    }
+
+    /// First $2006 write: bits 8-13 from `data`, bit 14 cleared
+    pub fn set_high_byte(&mut self, data: u8) {
+        self.value = (self.value & 0x00FF) | ((data as u16 & 0x3F) << 8);
+    }
+
+    /// Second $2006 write
+    pub fn set_low_byte(&mut self, data: u8) {
+        self.value = (self.value & 0x7F00) | data as u16;
+    }
+
+    /// $2007 access outside of rendering
+    pub fn increment(&mut self, inc: u8) {
+        self.value = self.value.wrapping_add(inc as u16) & 0x7FFF;
+    }
+
+    /// Next tile to the right, wrapping into the horizontally adjacent nametable
+    pub fn increment_x(&mut self) {
+        if self.coarse_x() == 31 {
+            self.value &= !COARSE_X;
+            self.value ^= NAMETABLE_X;
+        } else {
+            self.value += 1;
+        }
+    }
+
+    /// Next pixel row down. Coarse Y wraps at 29 into the vertically adjacent
+    /// nametable; rows 30 and 31 (attribute data) wrap to 0 without switching.
+    pub fn increment_y(&mut self) {
+        if self.fine_y() < 7 {
+            self.value += 1 << 12;
+            return;
+        }
+        self.value &= !FINE_Y;
+        match self.coarse_y() {
+            29 => {
+                self.set_coarse_y(0);
+                self.value ^= NAMETABLE_Y;
+            }
+            31 => self.set_coarse_y(0),
+            y => self.set_coarse_y(y as u8 + 1),
+        }
+    }
+
+    /// Dot 257: v: ....A.. ...BCDEF <- t: ....A.. ...BCDEF
+    pub fn copy_horizontal(&mut self, t: &LoopyRegister) {
+        let mask = COARSE_X | NAMETABLE_X;
+        self.value = (self.value & !mask) | (t.value & mask);
+    }
+
+    /// Pre-render dots 280-304: v: GHIA.BC DEF..... <- t: GHIA.BC DEF.....
+    pub fn copy_vertical(&mut self, t: &LoopyRegister) {
+        let mask = FINE_Y | NAMETABLE_Y | COARSE_Y;
+        self.value = (self.value & !mask) | (t.value & mask);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_increment_x_wraps_nametable() {
+        let mut v = LoopyRegister::new();
+        v.set_coarse_x(31);
+        v.increment_x();
+        assert_eq!(v.coarse_x(), 0);
+        assert_eq!(v.get(), NAMETABLE_X);
+    }
+
+    #[test]
+    fn test_increment_y() {
+        let mut v = LoopyRegister::new();
+        v.set_fine_y(7);
+        v.set_coarse_y(29);
+        v.increment_y();
+        assert_eq!((v.fine_y(), v.coarse_y()), (0, 0));
+        assert_eq!(v.get(), NAMETABLE_Y);
+
+        // Out-of-range rows wrap without switching nametables
+        v.set_fine_y(7);
+        v.set_coarse_y(31);
+        v.increment_y();
+        assert_eq!(v.get(), NAMETABLE_Y);
+    }
+
+    #[test]
+    fn test_copy_from_t() {
+        let mut v = LoopyRegister::new();
+        let mut t = LoopyRegister::new();
+        t.set(0x7FFF);
+        v.copy_horizontal(&t);
+        assert_eq!(v.get(), COARSE_X | NAMETABLE_X);
+        v.copy_vertical(&t);
+        assert_eq!(v.get(), 0x7FFF);
+    }
+}
@@ -1,9 +1,25 @@
-mod add_register;
+mod archive;
+mod battery;
 mod bus;
 mod controller_register;
 mod cpu;
+mod fds;
+mod info;
+mod loopy_register;
+mod mask_register;
+mod mmc1;
+mod nes;
+mod nes_header;
+mod opscode;
+mod palette;
+mod patch;
 mod ppu;
+mod region;
+mod rom_db;
 mod rom_loader;
+mod status_register;
+mod trace;
+mod unif;
 
 use sdl2::TimerSubsystem;
 use sdl2::event::Event;
@@ -11,24 +27,21 @@ use sdl2::keyboard::Keycode;
 use sdl2::pixels::PixelFormatEnum;
 
 use bus::Bus;
-use cpu::Cpu;
-use ppu::Ppu;
+use nes::Nes;
+use palette::Palette;
+use region::Region;
 use rom_loader::RomLoader;
 
 static NES_WIDTH: u64 = 256;
-static NES_HEIGHT: u64 = 240;
-static SCALE: u64 = 3;
-static FPS: u64 = 60;
-static FRAME_DELAY: u32 = 1000 / FPS as u32;
-
-static NES_PALETTE: [u32; 4] = [
-    0xFF7C7C7C, // gray
-    0xFF0000FF, // red
-    0xFF00FF00, // green
-    0xFFFF0000, // blue
-];
+static SAVE_INTERVAL_SECS: f64 = 5.0; // flush battery RAM every 5 seconds
 
 fn main() {
+    // nesBoy info [--json] ROM...
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("info") {
+        std::process::exit(info::run(args.skip(1)));
+    }
+
     let sdl = sdl2::init().unwrap();
     let video = sdl.video().unwrap();
     let window = video.window("NES", 256 * 3, 240 * 3).build().unwrap();
@@ -44,42 +57,89 @@ fn main() {
     let timer = sdl.timer().unwrap();
 
     let mut running = true;
-    let mut colorTimer: u32 = 0;
-    let mut color_index = 0;
 
-    // let mut rom_loader = RomLoader::new("ff.nes").unwrap();
-    // println!("Loaded ROM");
-    // rom_loader.print_info();
+    // nesBoy [rom.nes|rom.fds|rom.zip|rom.gz] [--entry NAME] [--patch FILE] [--fds-bios FILE]
+    //        [--palette FILE.pal] [--region ntsc|pal|dendy] [--trace]
+    let mut rom_path = "nestest.nes".to_string();
+    let mut zip_entry: Option<String> = None;
+    let mut patch_file: Option<String> = None;
+    let mut fds_bios = "disksys.rom".to_string();
+    let mut palette_file: Option<String> = None;
+    let mut region_name: Option<String> = None;
+    let mut trace = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--entry" => zip_entry = args.next(),
+            "--patch" => patch_file = args.next(),
+            "--fds-bios" => fds_bios = args.next().unwrap_or(fds_bios),
+            "--palette" => palette_file = args.next(),
+            "--region" => region_name = args.next(),
+            "--trace" => trace = true,
+            _ => rom_path = arg,
+        }
+    }
+
+    let palette = match palette_file {
+        Some(path) => Palette::load(&path).unwrap_or_else(|e| {
+            eprintln!("Failed to load palette {}: {}", path, e);
+            std::process::exit(1);
+        }),
+        None => Palette::default(),
+    };
+
+    let rom = RomLoader::open(&rom_path, zip_entry.as_deref(), patch_file.as_deref());
+    let mut bus = match rom.and_then(Bus::new) {
+        Ok(bus) => bus,
+        Err(e) => {
+            eprintln!("Failed to load {}: {}", rom_path, e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(patch) = bus.rom.patch() {
+        println!("Applied patch {}", patch.display());
+    }
+    if bus.is_fds()
+        && let Err(e) = bus.load_fds_bios(&fds_bios)
+    {
+        eprintln!(
+            "Failed to load FDS BIOS {}: {} (pass your disksys.rom with --fds-bios)",
+            fds_bios, e
+        );
+        std::process::exit(1);
+    }
+    let save_kind = if bus.is_fds() { "Disk writes" } else { "Battery RAM" };
+    match bus.attach_battery(&rom_path) {
+        Ok(Some(path)) => println!("{}: {}", save_kind, path.display()),
+        Ok(None) => {}
+        Err(e) => eprintln!("Could not load save file: {}", e),
+    }
+
+    // Auto-detected from the NES 2.0 header or the database unless overridden
+    let region = match region_name {
+        Some(name) => Region::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown region {} (expected ntsc, pal or dendy)", name);
+            std::process::exit(1);
+        }),
+        None => Region::from_timing(bus.rom.header().timing),
+    };
+    let mut nes = Nes::new(bus, region);
+    nes.set_trace(trace);
+    let mut frame_count: u64 = 0;
 
-    let mut bus = bus::Bus::new();
-    let mut cpu = cpu::Cpu::new(bus);
+    // 60.1 Hz NTSC, 50.0 Hz PAL/Dendy
+    let frame_period = 1000.0 / region.frame_rate();
+    let save_interval_frames = (SAVE_INTERVAL_SECS * region.frame_rate()) as u64;
+    let mut next_frame = TimerSubsystem::ticks(&timer) as f64;
 
     while running {
-        let frameStart = TimerSubsystem::ticks(&timer);
 
         // --- Emulate one frame ---
-        // Commented out for now until CPU/PPU are ready
-        // while (!bus.ppu->isFrameComplete()) {
-        cpu.step();
-        // }
-        // bus.ppu->resetFrameComplete();
+        nes.run_frame();
 
         // --- Convert PPU framebuffer indices to actual pixels ---
-
         texture
             .with_lock(None, |buffer: &mut [u8], pitch: usize| {
-                let color = NES_PALETTE[color_index % 4];
-                let r = ((color >> 16) & 0xFF) as u8;
-                let g = ((color >> 8) & 0xFF) as u8;
-                let b = (color & 0xFF) as u8;
-
-                // Fill each pixel (4 bytes per pixel)
-                for pixel in buffer.chunks_exact_mut(4) {
-                    pixel[0] = r; // Red
-                    pixel[1] = g; // Green
-                    pixel[2] = b; // Blue
-                    pixel[3] = 255; // Alpha
-                }
+                palette.frame_to_rgb(nes.frame(), NES_WIDTH as usize, buffer, pitch);
             })
             .unwrap();
 
@@ -89,37 +149,57 @@ fn main() {
 
         for event in sdl.event_pump().unwrap().poll_iter() {
             match event {
-                Event::KeyDown {
+                Event::Quit { .. }
+                | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => running = false,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } => {
+                    if let Some(side) = nes.cpu.bus.switch_disk_side() {
+                        let face = if side % 2 == 0 { 'A' } else { 'B' };
+                        println!("Inserting disk {} side {}", side / 2 + 1, face);
+                    }
+                }
                 _ => {}
             }
         }
 
-        let frameTime = TimerSubsystem::ticks(&timer) - frameStart;
-        if FRAME_DELAY > frameTime {
-            timer.delay(FRAME_DELAY - frameTime);
+        frame_count += 1;
+        if frame_count.is_multiple_of(save_interval_frames)
+            && let Err(e) = nes.cpu.bus.flush_battery()
+        {
+            eprintln!("Could not write save file: {}", e);
         }
 
-        // // Update color every 2 seconds
-        colorTimer += frameTime;
-        if (colorTimer >= 200) {
-            color_index += 1;
-            colorTimer = 0;
-            println!("Changed color to index: {}", color_index % 4);
+        // Pace against a running deadline so fractional frame periods add up;
+        // if we fall behind, don't try to catch up
+        next_frame += frame_period;
+        let now = TimerSubsystem::ticks(&timer) as f64;
+        if next_frame > now {
+            timer.delay((next_frame - now) as u32);
+        } else {
+            next_frame = now;
         }
     }
+
+    if let Err(e) = nes.cpu.bus.flush_battery() {
+        eprintln!("Could not write save file: {}", e);
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::bus::Bus;
+    use crate::cpu::Cpu;
+    use crate::trace::trace;
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new();
+        let mut bus = Bus::new(RomLoader::new("nestest.nes").unwrap()).unwrap();
         bus.write(100, 0xa2);
         bus.write(101, 0x01);
         bus.write(102, 0xca);
@@ -151,14 +231,14 @@ mod test {
 
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new();
+        let mut bus = Bus::new(RomLoader::new("nestest.nes").unwrap()).unwrap();
         // ORA ($33), Y
         bus.write(100, 0x11);
         bus.write(101, 0x33);
 
         //data
-        bus.write(0x33, 00);
-        bus.write(0x34, 04);
+        bus.write(0x33, 0x00);
+        bus.write(0x34, 0x04);
 
         //target cell
         bus.write(0x400, 0xAA);
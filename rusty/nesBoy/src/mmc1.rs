@@ -0,0 +1,136 @@
+// Nintendo MMC1 (iNES mapper 1): SxROM boards
+// https://www.nesdev.org/wiki/MMC1
+
+use crate::ppu::Mirroring;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+pub struct Mmc1 {
+    shift: u8, // serial load register, bits arrive LSB first
+    shift_count: u8,
+    control: u8, // CPPMM: CHR mode, PRG mode, mirroring
+    chr_bank: [u8; 2],
+    prg_bank: u8, // bit 4 disables PRG-RAM
+    prg_banks: usize,
+}
+
+impl Mmc1 {
+    /// Power-on state: PRG mode 3, the last 16K bank fixed at $C000
+    pub fn new(prg_rom_size: usize) -> Self {
+        Mmc1 {
+            shift: 0,
+            shift_count: 0,
+            control: 0x0C,
+            chr_bank: [0, 0],
+            prg_bank: 0,
+            prg_banks: (prg_rom_size / PRG_BANK_SIZE).max(1),
+        }
+    }
+
+    /// $8000-$FFFF: five writes of bit 0 fill a register picked by address
+    /// bits 13-14 on the last write. Bit 7 resets the shift register.
+    pub fn write(&mut self, addr: u16, data: u8) {
+        if data & 0x80 != 0 {
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            return;
+        }
+        self.shift |= (data & 0x01) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count < 5 {
+            return;
+        }
+        let value = self.shift;
+        self.shift = 0;
+        self.shift_count = 0;
+        match addr & 0xE000 {
+            0x8000 => self.control = value,
+            0xA000 => self.chr_bank[0] = value,
+            0xC000 => self.chr_bank[1] = value,
+            _ => self.prg_bank = value,
+        }
+    }
+
+    /// Offset into PRG-ROM for a CPU read at $8000-$FFFF
+    pub fn prg_offset(&self, addr: u16) -> usize {
+        // SUROM: CHR bank bit 4 selects the 256K half of a 512K PRG-ROM
+        let outer = (self.chr_bank[0] as usize & 0x10) & (self.prg_banks - 1);
+        let bank = self.prg_bank as usize & 0x0F;
+        let last = (self.prg_banks - 1) & 0x0F;
+        let bank = match (self.control >> 2) & 0x03 {
+            // 32K mode ignores the low bit of the bank number
+            0 | 1 => (bank & !1) | ((addr as usize >> 14) & 1),
+            2 if addr < 0xC000 => 0,
+            2 => bank,
+            _ if addr < 0xC000 => bank,
+            _ => last,
+        };
+        ((outer | bank) % self.prg_banks) * PRG_BANK_SIZE + (addr as usize & 0x3FFF)
+    }
+
+    /// Offsets of the two 4K pattern tables into CHR
+    pub fn chr_banks(&self) -> [usize; 2] {
+        if self.control & 0x10 != 0 {
+            [
+                self.chr_bank[0] as usize * CHR_BANK_SIZE,
+                self.chr_bank[1] as usize * CHR_BANK_SIZE,
+            ]
+        } else {
+            let bank = (self.chr_bank[0] & 0x1E) as usize * CHR_BANK_SIZE;
+            [bank, bank + CHR_BANK_SIZE]
+        }
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::SINGLE_SCREEN_A,
+            1 => Mirroring::SINGLE_SCREEN_B,
+            2 => Mirroring::VERTICAL,
+            _ => Mirroring::HORIZONTAL,
+        }
+    }
+
+    pub fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn load(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for i in 0..5 {
+            mmc1.write(addr, (value >> i) & 1);
+        }
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut mmc1 = Mmc1::new(16 * PRG_BANK_SIZE);
+        assert_eq!(mmc1.prg_offset(0xC000), 15 * PRG_BANK_SIZE);
+
+        // PRG mode 3: switchable $8000, fixed last bank at $C000
+        load(&mut mmc1, 0xE000, 0x03);
+        assert_eq!(mmc1.prg_offset(0x8001), 3 * PRG_BANK_SIZE + 1);
+        assert_eq!(mmc1.prg_offset(0xFFFF), 16 * PRG_BANK_SIZE - 1);
+
+        // CHR mode 1 with vertical mirroring, PRG mode 2: fixed first bank
+        load(&mut mmc1, 0x8000, 0x1A);
+        assert_eq!(mmc1.mirroring(), Mirroring::VERTICAL);
+        assert_eq!(mmc1.prg_offset(0x8000), 0);
+        assert_eq!(mmc1.prg_offset(0xC000), 3 * PRG_BANK_SIZE);
+        load(&mut mmc1, 0xA000, 0x05);
+        load(&mut mmc1, 0xC000, 0x02);
+        assert_eq!(mmc1.chr_banks(), [5 * CHR_BANK_SIZE, 2 * CHR_BANK_SIZE]);
+
+        // A reset mid-load throws away the partial value
+        mmc1.write(0xE000, 0x01);
+        mmc1.write(0xE000, 0x80);
+        assert_eq!(mmc1.prg_offset(0xC000), 15 * PRG_BANK_SIZE);
+        load(&mut mmc1, 0xE000, 0x10);
+        assert!(!mmc1.prg_ram_enabled());
+    }
+}
@@ -0,0 +1,117 @@
+// Top-level console: runs the CPU and keeps the PPU in step with it
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::region::Region;
+use crate::trace::trace;
+
+pub struct Nes {
+    pub cpu: Cpu,
+    region: Region,
+    // Master clocks the PPU is behind the CPU. The CPU and PPU dividers give
+    // 3 dots per CPU cycle on NTSC and Dendy, 3.2 on PAL.
+    master_clocks: u32,
+    trace: bool, // print a nestest-style line before every instruction
+}
+
+impl Nes {
+    /// Power on: the CPU starts at the reset vector
+    pub fn new(mut bus: Bus, region: Region) -> Self {
+        bus.ppu.set_region(region);
+        let pc = u16::from_le_bytes([bus.read(0xFFFC), bus.read(0xFFFD)]);
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        cpu.pc = pc;
+        Nes {
+            cpu,
+            region,
+            master_clocks: 0,
+            trace: false,
+        }
+    }
+
+    /// Run one CPU instruction (or interrupt/DMA stall) and the PPU dots that
+    /// elapse during it, returning the CPU cycles used
+    pub fn step(&mut self) -> u16 {
+        if self.trace {
+            println!("{}", trace(&mut self.cpu));
+        }
+        let cycles = self.cpu.step();
+        self.master_clocks += cycles as u32 * self.region.cpu_divider();
+        let ppu_divider = self.region.ppu_divider();
+        while self.master_clocks >= ppu_divider {
+            self.master_clocks -= ppu_divider;
+            self.cpu.bus.ppu.step();
+        }
+        cycles
+    }
+
+    /// Log every instruction to stdout in nestest format before it runs
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Run until the PPU finishes the current frame
+    pub fn run_frame(&mut self) {
+        while !self.cpu.bus.ppu.is_frame_complete() {
+            self.step();
+        }
+        self.cpu.bus.ppu.reset_frame_complete();
+    }
+
+    /// Colour indices (with emphasis bits) of the last finished frame
+    pub fn frame(&self) -> &[u16; 256 * 240] {
+        self.cpu.bus.ppu.get_frame()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom_loader::RomLoader;
+
+    fn nes(region: Region) -> Nes {
+        // NES 2.0 NROM whose reset vector points at an infinite JMP
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 2, 1, 0, 0x08];
+        data.resize(16, 0);
+        let mut prg = vec![0xEA; 32768];
+        prg[0..3].copy_from_slice(&[0x4C, 0x00, 0x80]); // JMP $8000
+        prg[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0x80]);
+        data.extend(prg);
+        data.extend(vec![0; 8192]);
+        let bus = Bus::new(RomLoader::from_bytes(&data).unwrap()).unwrap();
+        Nes::new(bus, region)
+    }
+
+    #[test]
+    fn test_run_frame() {
+        // CPU cycles per frame, give or take one instruction
+        for (region, expected) in [
+            (Region::Ntsc, 29781),
+            (Region::Pal, 33248),
+            (Region::Dendy, 35464),
+        ] {
+            let mut nes = nes(region);
+            assert_eq!(nes.cpu.pc, 0x8000);
+            nes.run_frame();
+            assert!(!nes.cpu.bus.ppu.is_frame_complete());
+
+            let mut cycles = 0;
+            while !nes.cpu.bus.ppu.is_frame_complete() {
+                cycles += nes.step() as u32;
+            }
+            assert!(cycles.abs_diff(expected) <= 3, "{:?}: {}", region, cycles);
+        }
+    }
+
+    #[test]
+    fn test_pal_dot_ratio() {
+        let mut nes = nes(Region::Pal);
+        let cycles: u16 = (0..5).map(|_| nes.step()).sum();
+
+        // Five 3-cycle JMPs: 15 cycles, 48 dots
+        assert_eq!(cycles, 15);
+        assert_eq!(nes.master_clocks, 0);
+    }
+}
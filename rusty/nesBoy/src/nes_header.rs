@@ -0,0 +1,320 @@
+// iNES / NES 2.0 header
+// https://www.nesdev.org/wiki/INES
+// https://www.nesdev.org/wiki/NES_2.0
+
+use crate::ppu::Mirroring;
+use crate::rom_loader::RomError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderFormat {
+    INes,
+    Nes20,
+    Fds,  // synthesized for disk images, which have no iNES header
+    Unif, // synthesized from UNIF chunks
+}
+
+// CPU/PPU timing (NES 2.0 byte 12, iNES flags9 bit 0)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,        // RP2C02
+    Pal,         // RP2C07
+    MultiRegion, // runs on both
+    Dendy,       // UMC 6527P
+}
+
+// flags7 bits 0-1, extended type in byte 13 when bits are 3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem { ppu_type: u8, hardware_type: u8 },
+    Playchoice10,
+    Extended(u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct NesHeader {
+    pub format: HeaderFormat,
+    pub prg_rom_size: usize, // in bytes
+    pub chr_rom_size: usize, // in bytes, 0 means the board uses CHR-RAM
+    pub mapper: u16,
+    pub submapper: u8,
+    pub vertical_mirroring: bool,
+    pub four_screen_mode: bool,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub prg_ram_size: usize,   // volatile PRG-RAM in bytes
+    pub prg_nvram_size: usize, // battery-backed PRG-RAM in bytes
+    pub chr_ram_size: usize,   // volatile CHR-RAM in bytes
+    pub chr_nvram_size: usize, // battery-backed CHR-RAM in bytes
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_roms: u8,
+    pub default_expansion_device: u8,
+    pub raw: [u8; 16],
+}
+
+impl NesHeader {
+    pub const SIZE: usize = 16;
+
+    pub fn parse(bytes: &[u8; 16]) -> Result<Self, RomError> {
+        // Validate the signature is "NES\x1A"
+        if &bytes[0..3] != b"NES" || bytes[3] != 0x1A {
+            return Err(RomError::BadMagic);
+        }
+
+        let flags6 = bytes[6];
+        let flags7 = bytes[7];
+        let is_nes20 = flags7 & 0x0C == 0x08;
+
+        let vertical_mirroring = (flags6 & 0x01) != 0;
+        let has_battery = (flags6 & 0x02) != 0;
+        let has_trainer = (flags6 & 0x04) != 0;
+        let four_screen_mode = (flags6 & 0x08) != 0;
+
+        if is_nes20 {
+            Self::parse_nes20(
+                bytes,
+                vertical_mirroring,
+                four_screen_mode,
+                has_battery,
+                has_trainer,
+            )
+        } else {
+            Ok(Self::parse_ines(
+                bytes,
+                vertical_mirroring,
+                four_screen_mode,
+                has_battery,
+                has_trainer,
+            ))
+        }
+    }
+
+    fn parse_ines(
+        bytes: &[u8; 16],
+        vertical_mirroring: bool,
+        four_screen_mode: bool,
+        has_battery: bool,
+        has_trainer: bool,
+    ) -> Self {
+        // Old dumpers ("DiskDude!") wrote garbage into bytes 7-15, in which
+        // case flags7-9 can't be trusted and fall back to their defaults
+        let [flags7, flags8, flags9] = if Self::has_dumper_garbage(bytes) {
+            [0; 3]
+        } else {
+            [bytes[7], bytes[8], bytes[9]]
+        };
+        let mapper = ((flags7 & 0xF0) | (bytes[6] >> 4)) as u16;
+
+        let chr_rom_size = bytes[5] as usize * 8192;
+        // flags8 is PRG-RAM in 8KB units, 0 infers 8KB for compatibility
+        let prg_ram_size = flags8.max(1) as usize * 8192;
+
+        NesHeader {
+            format: HeaderFormat::INes,
+            prg_rom_size: bytes[4] as usize * 16384,
+            chr_rom_size,
+            mapper,
+            submapper: 0,
+            vertical_mirroring,
+            four_screen_mode,
+            has_battery,
+            has_trainer,
+            prg_ram_size: if has_battery { 0 } else { prg_ram_size },
+            prg_nvram_size: if has_battery { prg_ram_size } else { 0 },
+            chr_ram_size: if chr_rom_size == 0 { 8192 } else { 0 },
+            chr_nvram_size: 0,
+            timing: if flags9 & 0x01 != 0 {
+                Timing::Pal
+            } else {
+                Timing::Ntsc
+            },
+            console_type: match flags7 & 0x03 {
+                1 => ConsoleType::VsSystem {
+                    ppu_type: 0,
+                    hardware_type: 0,
+                },
+                2 => ConsoleType::Playchoice10,
+                _ => ConsoleType::Nes,
+            },
+            misc_roms: 0,
+            default_expansion_device: 0,
+            raw: *bytes,
+        }
+    }
+
+    /// True when bytes 12-15 of an iNES header are nonzero, a sign that an
+    /// old dumper tool wrote its name over the unused header bytes
+    pub fn has_dumper_garbage(bytes: &[u8; 16]) -> bool {
+        bytes[12..16].iter().any(|&b| b != 0)
+    }
+
+    fn parse_nes20(
+        bytes: &[u8; 16],
+        vertical_mirroring: bool,
+        four_screen_mode: bool,
+        has_battery: bool,
+        has_trainer: bool,
+    ) -> Result<Self, RomError> {
+        let mapper =
+            ((bytes[8] as u16 & 0x0F) << 8) | (bytes[7] & 0xF0) as u16 | (bytes[6] >> 4) as u16;
+        let submapper = bytes[8] >> 4;
+
+        let prg_rom_size = Self::rom_size(bytes[4], bytes[9] & 0x0F, 16384)
+            .ok_or(RomError::BadNes20Size("PRG-ROM"))?;
+        let chr_rom_size = Self::rom_size(bytes[5], bytes[9] >> 4, 8192)
+            .ok_or(RomError::BadNes20Size("CHR-ROM"))?;
+
+        let console_type = match bytes[7] & 0x03 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem {
+                ppu_type: bytes[13] & 0x0F,
+                hardware_type: bytes[13] >> 4,
+            },
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(bytes[13] & 0x0F),
+        };
+
+        Ok(NesHeader {
+            format: HeaderFormat::Nes20,
+            prg_rom_size,
+            chr_rom_size,
+            mapper,
+            submapper,
+            vertical_mirroring,
+            four_screen_mode,
+            has_battery,
+            has_trainer,
+            prg_ram_size: Self::ram_size(bytes[10] & 0x0F),
+            prg_nvram_size: Self::ram_size(bytes[10] >> 4),
+            chr_ram_size: Self::ram_size(bytes[11] & 0x0F),
+            chr_nvram_size: Self::ram_size(bytes[11] >> 4),
+            timing: match bytes[12] & 0x03 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy,
+            },
+            console_type,
+            misc_roms: bytes[14] & 0x03,
+            default_expansion_device: bytes[15] & 0x3F,
+            raw: *bytes,
+        })
+    }
+
+    // ROM sizes are either a 12-bit unit count (LSB byte + MSB nibble) or,
+    // when the MSB nibble is $F, an exponent-multiplier: 2^E * (MM*2+1)
+    fn rom_size(lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+        if msb == 0x0F {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0x03) as usize * 2 + 1;
+            1usize.checked_shl(exponent)?.checked_mul(multiplier)
+        } else {
+            Some((((msb as usize) << 8) | lsb as usize) * unit)
+        }
+    }
+
+    // RAM sizes are stored as shift counts: 64 << n, 0 means none
+    fn ram_size(shift: u8) -> usize {
+        if shift == 0 { 0 } else { 64 << shift }
+    }
+
+    /// Header describing the RAM adapter a disk image runs on (iNES mapper 20):
+    /// 32KB of PRG-RAM, 8KB of CHR-RAM and no ROM besides the BIOS
+    pub fn fds() -> Self {
+        NesHeader {
+            format: HeaderFormat::Fds,
+            prg_rom_size: 0,
+            chr_rom_size: 0,
+            mapper: 20,
+            submapper: 0,
+            vertical_mirroring: false,
+            four_screen_mode: false,
+            has_battery: false,
+            has_trainer: false,
+            prg_ram_size: 0x8000,
+            prg_nvram_size: 0,
+            chr_ram_size: 0x2000,
+            chr_nvram_size: 0,
+            timing: Timing::Ntsc,
+            console_type: ConsoleType::Nes,
+            misc_roms: 0,
+            default_expansion_device: 0,
+            raw: [0; 16],
+        }
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        if self.four_screen_mode {
+            Mirroring::FOUR_SCREEN
+        } else if self.vertical_mirroring {
+            Mirroring::VERTICAL
+        } else {
+            Mirroring::HORIZONTAL
+        }
+    }
+
+    pub fn is_nes20(&self) -> bool {
+        self.format == HeaderFormat::Nes20
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(bytes: [u8; 12]) -> [u8; 16] {
+        let mut h = [0u8; 16];
+        h[0..4].copy_from_slice(b"NES\x1A");
+        h[4..16].copy_from_slice(&bytes);
+        h
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let h = NesHeader::parse(&header([2, 1, 0x13, 0x40, 0, 1, 0, 0, 0, 0, 0, 0])).unwrap();
+        assert_eq!(h.format, HeaderFormat::INes);
+        assert_eq!(h.prg_rom_size, 32768);
+        assert_eq!(h.chr_rom_size, 8192);
+        assert_eq!(h.mapper, 0x41);
+        assert!(h.vertical_mirroring);
+        assert!(h.has_battery);
+        assert_eq!(h.prg_nvram_size, 8192);
+        assert_eq!(h.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn test_ines_garbage_ignores_flags7_to_9() {
+        let h = NesHeader::parse(&header([
+            1, 1, 0x10, 0x44, 0x69, 0x73, 0x6B, 0x44, 0x75, 0x64, 0x65, 0x21,
+        ]))
+        .unwrap();
+        assert_eq!(h.mapper, 1);
+        assert_eq!(h.prg_ram_size, 8192);
+        assert_eq!(h.timing, Timing::Ntsc);
+    }
+
+    #[test]
+    fn test_nes20_header() {
+        let h = NesHeader::parse(&header([
+            0x02, 0x00, 0x41, 0x08, 0x21, 0x00, 0x70, 0x07, 0x03, 0x00, 0x00, 0x01,
+        ]))
+        .unwrap();
+        assert_eq!(h.format, HeaderFormat::Nes20);
+        assert_eq!(h.mapper, 0x104);
+        assert_eq!(h.submapper, 2);
+        assert_eq!(h.prg_rom_size, 32768);
+        assert_eq!(h.chr_rom_size, 0);
+        assert_eq!(h.prg_nvram_size, 8192);
+        assert_eq!(h.chr_ram_size, 8192);
+        assert_eq!(h.timing, Timing::Dendy);
+        assert_eq!(h.default_expansion_device, 1);
+    }
+
+    #[test]
+    fn test_nes20_exponent_multiplier_size() {
+        // PRG: E=10, MM=1 -> 1024 * 3 bytes
+        let h = NesHeader::parse(&header([0x29, 0, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0])).unwrap();
+        assert_eq!(h.prg_rom_size, 3072);
+    }
+}
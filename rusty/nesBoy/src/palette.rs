@@ -0,0 +1,140 @@
+// NES colour palettes: PPU colour indices to RGB
+// https://www.nesdev.org/wiki/PPU_palettes
+//
+// .pal files are raw RGB triplets, either 64 colours or 512 (the 64 colours
+// under each of the 8 PPUMASK emphasis combinations, emphasis in bits 6-8)
+
+use std::fs;
+use std::io;
+
+const COLORS: usize = 64;
+const EMPHASIS_COLORS: usize = COLORS * 8;
+
+// Non-emphasised channels are darkened to about 3/4 per emphasis bit
+const EMPHASIS_ATTENUATION: f32 = 0.75;
+
+#[rustfmt::skip]
+const DEFAULT_PALETTE: [[u8; 3]; COLORS] = [
+    [84, 84, 84],    [0, 30, 116],    [8, 16, 144],    [48, 0, 136],
+    [68, 0, 100],    [92, 0, 48],     [84, 4, 0],      [60, 24, 0],
+    [32, 42, 0],     [8, 58, 0],      [0, 64, 0],      [0, 60, 0],
+    [0, 50, 60],     [0, 0, 0],       [0, 0, 0],       [0, 0, 0],
+    [152, 150, 152], [8, 76, 196],    [48, 50, 236],   [92, 30, 228],
+    [136, 20, 176],  [160, 20, 100],  [152, 34, 32],   [120, 60, 0],
+    [84, 90, 0],     [40, 114, 0],    [8, 124, 0],     [0, 118, 40],
+    [0, 102, 120],   [0, 0, 0],       [0, 0, 0],       [0, 0, 0],
+    [236, 238, 236], [76, 154, 236],  [120, 124, 236], [176, 98, 236],
+    [228, 84, 236],  [236, 88, 180],  [236, 106, 100], [212, 136, 32],
+    [160, 170, 0],   [116, 196, 0],   [76, 208, 32],   [56, 204, 108],
+    [56, 180, 204],  [60, 60, 60],    [0, 0, 0],       [0, 0, 0],
+    [236, 238, 236], [168, 204, 236], [188, 188, 236], [212, 178, 236],
+    [236, 174, 236], [236, 174, 212], [236, 180, 176], [228, 196, 144],
+    [204, 210, 120], [180, 222, 120], [168, 226, 144], [152, 226, 180],
+    [160, 214, 228], [160, 162, 160], [0, 0, 0],       [0, 0, 0],
+];
+
+pub struct Palette {
+    colors: Vec<[u8; 3]>, // always 512 entries
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::from_colors(&DEFAULT_PALETTE)
+    }
+}
+
+impl Palette {
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        if data.len() != COLORS * 3 && data.len() != EMPHASIS_COLORS * 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "palette is {} bytes, expected {} or {}",
+                    data.len(),
+                    COLORS * 3,
+                    EMPHASIS_COLORS * 3
+                ),
+            ));
+        }
+        let colors: Vec<[u8; 3]> = data
+            .chunks_exact(3)
+            .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+            .collect();
+        Ok(Self::from_colors(&colors))
+    }
+
+    // 64-colour palettes get their emphasis variants synthesised
+    fn from_colors(colors: &[[u8; 3]]) -> Self {
+        if colors.len() == EMPHASIS_COLORS {
+            return Palette {
+                colors: colors.to_vec(),
+            };
+        }
+        let mut out = Vec::with_capacity(EMPHASIS_COLORS);
+        for emphasis in 0..8 {
+            for rgb in colors {
+                let mut rgb = *rgb;
+                for bit in (0..3).filter(|b| emphasis & (1 << b) != 0) {
+                    for (channel, value) in rgb.iter_mut().enumerate() {
+                        if channel != bit {
+                            *value = (*value as f32 * EMPHASIS_ATTENUATION) as u8;
+                        }
+                    }
+                }
+                out.push(rgb);
+            }
+        }
+        Palette { colors: out }
+    }
+
+    /// RGB for a colour index, with the PPUMASK emphasis bits in bits 6-8
+    pub fn rgb(&self, index: u16) -> [u8; 3] {
+        self.colors[index as usize % EMPHASIS_COLORS]
+    }
+
+    /// Convert a frame of colour indices (with emphasis) into RGB24 rows `pitch` bytes apart
+    pub fn frame_to_rgb(&self, frame: &[u16], width: usize, buffer: &mut [u8], pitch: usize) {
+        for (y, row) in frame.chunks_exact(width).enumerate() {
+            for (x, &index) in row.iter().enumerate() {
+                let offset = y * pitch + x * 3;
+                buffer[offset..offset + 3].copy_from_slice(&self.rgb(index));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_sizes() {
+        let palette = Palette::from_bytes(&[0x80; COLORS * 3]).unwrap();
+        assert_eq!(palette.rgb(0x3F), [0x80, 0x80, 0x80]);
+        // Red emphasis darkens green and blue, all three darken everything
+        assert_eq!(palette.rgb(0x40), [0x80, 0x60, 0x60]);
+        assert_eq!(palette.rgb(0x1C0), [0x48, 0x48, 0x48]);
+
+        let mut data = vec![0; EMPHASIS_COLORS * 3];
+        data[0x1FF * 3..].copy_from_slice(&[1, 2, 3]);
+        let palette = Palette::from_bytes(&data).unwrap();
+        assert_eq!(palette.rgb(0x1FF), [1, 2, 3]);
+
+        assert!(Palette::from_bytes(&[0; 100]).is_err());
+    }
+
+    #[test]
+    fn test_frame_to_rgb() {
+        let palette = Palette::default();
+        let frame = [0x00, 0x30, 0x0F, 0x16];
+        let mut buffer = [0xAA; 2 * 8];
+        palette.frame_to_rgb(&frame, 2, &mut buffer, 8);
+        assert_eq!(&buffer[0..6], &[84, 84, 84, 236, 238, 236]);
+        assert_eq!(&buffer[6..8], &[0xAA, 0xAA]); // row padding untouched
+        assert_eq!(&buffer[8..14], &[0, 0, 0, 152, 34, 32]);
+    }
+}
@@ -0,0 +1,456 @@
+// Soft-patching of ROM images before they are parsed
+// IPS: https://zerosoft.zophar.net/ips.php
+// UPS: https://www.romhacking.net/documents/392/
+// BPS: https://www.romhacking.net/documents/746/
+
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::archive;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+// Largest target a UPS/BPS header may declare before we allocate for it
+const MAX_TARGET_SIZE: usize = 16 * 1024 * 1024;
+
+// Sibling extensions checked when looking for a patch next to the ROM
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated,
+    // The patch was made against a different ROM
+    SourceMismatch { expected: u32, found: u32 },
+    TargetMismatch { expected: u32, found: u32 },
+    PatchChecksumMismatch,
+    OutOfBounds,
+    TargetTooLarge(usize),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Truncated => write!(f, "patch file is truncated"),
+            PatchError::SourceMismatch { expected, found } => write!(
+                f,
+                "patch is for a different ROM (expects CRC32 {:08X}, ROM is {:08X})",
+                expected, found
+            ),
+            PatchError::TargetMismatch { expected, found } => write!(
+                f,
+                "patched ROM has CRC32 {:08X}, patch expects {:08X}",
+                found, expected
+            ),
+            PatchError::PatchChecksumMismatch => write!(f, "patch file is corrupt (bad CRC32)"),
+            PatchError::OutOfBounds => write!(f, "patch reads outside the ROM image"),
+            PatchError::TargetTooLarge(size) => {
+                write!(f, "patch target of {} bytes is too large", size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl PatchFormat {
+    pub fn detect(patch: &[u8]) -> Option<Self> {
+        if patch.starts_with(IPS_MAGIC) {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(UPS_MAGIC) {
+            Some(PatchFormat::Ups)
+        } else if patch.starts_with(BPS_MAGIC) {
+            Some(PatchFormat::Bps)
+        } else {
+            None
+        }
+    }
+}
+
+/// Look for game.ips / game.ups / game.bps next to game.nes (or game.zip,
+/// game.nes.gz)
+pub fn find_patch(rom_path: &str) -> Option<PathBuf> {
+    let mut base = PathBuf::from(rom_path);
+    if archive::is_archive_name(&base) {
+        base.set_extension("");
+    }
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|ext| base.with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+/// Apply an IPS, UPS or BPS patch to a raw ROM image
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match PatchFormat::detect(patch) {
+        Some(PatchFormat::Ips) => apply_ips(rom, patch),
+        Some(PatchFormat::Ups) => apply_ups(rom, patch),
+        Some(PatchFormat::Bps) => apply_bps(rom, patch),
+        None => Err(PatchError::UnknownFormat),
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let slice = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(PatchError::Truncated)?;
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | b as usize))
+    }
+
+    // UPS/BPS variable-length integer: 7 bits per byte, high bit ends the
+    // number, and each continuation adds an implicit 1 so encodings are unique
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x = self.byte()?;
+            value = value
+                .checked_add((x & 0x7F) as usize * shift)
+                .ok_or(PatchError::OutOfBounds)?;
+            if x & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::OutOfBounds)?;
+            value = value.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+        }
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader::new(patch, IPS_MAGIC.len());
+
+    loop {
+        let record = reader.bytes(3)?;
+        if record == IPS_EOF {
+            break;
+        }
+        let offset = record.iter().fold(0, |acc, &b| (acc << 8) | b as usize);
+        let size = reader.be(2)?;
+
+        // size 0 is a run-length record: 2-byte count and 1 fill byte
+        let (len, data) = if size == 0 {
+            let count = reader.be(2)?;
+            (count, None)
+        } else {
+            (size, Some(reader.bytes(size)?))
+        };
+
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        match data {
+            Some(data) => out[offset..offset + len].copy_from_slice(data),
+            None => {
+                let value = reader.byte()?;
+                out[offset..offset + len].fill(value);
+            }
+        }
+    }
+
+    // Optional truncation extension after EOF
+    if patch.len() - reader.pos == 3 {
+        out.truncate(reader.be(3)?);
+    }
+
+    Ok(out)
+}
+
+// UPS and BPS share a footer: source, target and patch CRC32s.
+// Returns the target CRC once the patch and source ROM have been verified.
+fn check_footer(rom: &[u8], patch: &[u8]) -> Result<u32, PatchError> {
+    if patch.len() < 12 {
+        return Err(PatchError::Truncated);
+    }
+    let footer = &patch[patch.len() - 12..];
+    let crc = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    let (source_crc, target_crc, patch_crc) =
+        (crc(&footer[0..4]), crc(&footer[4..8]), crc(&footer[8..12]));
+
+    if crc32fast::hash(&patch[..patch.len() - 4]) != patch_crc {
+        return Err(PatchError::PatchChecksumMismatch);
+    }
+    let rom_crc = crc32fast::hash(rom);
+    if rom_crc != source_crc {
+        return Err(PatchError::SourceMismatch {
+            expected: source_crc,
+            found: rom_crc,
+        });
+    }
+    Ok(target_crc)
+}
+
+fn check_target(out: &[u8], target_crc: u32) -> Result<(), PatchError> {
+    let out_crc = crc32fast::hash(out);
+    if out_crc != target_crc {
+        return Err(PatchError::TargetMismatch {
+            expected: target_crc,
+            found: out_crc,
+        });
+    }
+    Ok(())
+}
+
+fn check_target_size(target_size: usize) -> Result<usize, PatchError> {
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::TargetTooLarge(target_size));
+    }
+    Ok(target_size)
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = check_footer(rom, patch)?;
+    let end = patch.len() - 12;
+    let mut reader = Reader::new(&patch[..end], UPS_MAGIC.len());
+
+    // Target bytes past the end of the source start out zero, so an expanding
+    // patch needs no hunk bytes for the parts that stay zero
+    let _source_size = reader.varint()?;
+    let target_size = check_target_size(reader.varint()?)?;
+
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+
+    // Hunks: skip `offset` bytes, then XOR until (and including) a zero byte
+    let mut pos: usize = 0;
+    while reader.pos < end {
+        pos = pos
+            .checked_add(reader.varint()?)
+            .ok_or(PatchError::OutOfBounds)?;
+        loop {
+            let x = reader.byte()?;
+            if let Some(b) = out.get_mut(pos) {
+                *b ^= x;
+            }
+            pos = pos.checked_add(1).ok_or(PatchError::OutOfBounds)?;
+            if x == 0 {
+                break;
+            }
+        }
+    }
+
+    check_target(&out, target_crc)?;
+    Ok(out)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = check_footer(rom, patch)?;
+    let end = patch.len() - 12;
+    let mut reader = Reader::new(&patch[..end], BPS_MAGIC.len());
+
+    // TargetCopy can repeat output, so only a fixed bound applies here
+    let _source_size = reader.varint()?;
+    let target_size = check_target_size(reader.varint()?)?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+
+    let mut out: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+
+    // Copy offsets are signed: bit 0 is the sign, the rest the magnitude
+    let relative = |reader: &mut Reader, offset: &mut isize| -> Result<usize, PatchError> {
+        let data = reader.varint()?;
+        let delta = isize::try_from(data >> 1).map_err(|_| PatchError::OutOfBounds)?;
+        *offset = if data & 1 != 0 {
+            offset.checked_sub(delta)
+        } else {
+            offset.checked_add(delta)
+        }
+        .ok_or(PatchError::OutOfBounds)?;
+        usize::try_from(*offset).map_err(|_| PatchError::OutOfBounds)
+    };
+
+    while reader.pos < end {
+        let data = reader.varint()?;
+        let len = (data >> 2) + 1;
+        // No command may write past the declared target
+        if len > target_size - out.len() {
+            return Err(PatchError::OutOfBounds);
+        }
+        match data & 0x03 {
+            // SourceRead: same position in the source
+            0 => {
+                let start = out.len();
+                let bytes = rom.get(start..start + len).ok_or(PatchError::OutOfBounds)?;
+                out.extend_from_slice(bytes);
+            }
+            // TargetRead: literal bytes from the patch
+            1 => out.extend_from_slice(reader.bytes(len)?),
+            // SourceCopy: anywhere in the source
+            2 => {
+                let start = relative(&mut reader, &mut source_offset)?;
+                let bytes = rom.get(start..start + len).ok_or(PatchError::OutOfBounds)?;
+                out.extend_from_slice(bytes);
+                source_offset += len as isize;
+            }
+            // TargetCopy: earlier output, may overlap what is being written
+            _ => {
+                let start = relative(&mut reader, &mut target_offset)?;
+                for i in start..start + len {
+                    let b = *out.get(i).ok_or(PatchError::OutOfBounds)?;
+                    out.push(b);
+                }
+                target_offset += len as isize;
+            }
+        }
+    }
+
+    if out.len() != target_size {
+        return Err(PatchError::Truncated);
+    }
+    check_target(&out, target_crc)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn varint(mut n: usize, out: &mut Vec<u8>) {
+        loop {
+            let x = (n & 0x7F) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(0x80 | x);
+                break;
+            }
+            out.push(x);
+            n -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(crc32fast::hash(source).to_le_bytes());
+        patch.extend(crc32fast::hash(target).to_le_bytes());
+        patch.extend(crc32fast::hash(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_ips() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend([0x00, 0x00, 0x01, 0x00, 0x02, b'x', b'y']);
+        // RLE record past the end grows the image
+        patch.extend([0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x02, b'z']);
+        patch.extend(b"EOF");
+
+        assert_eq!(apply(b"abcd", &patch).unwrap(), b"axyd\0zz");
+    }
+
+    #[test]
+    fn test_ups() {
+        let (source, target) = (b"ABCD", b"ABXDE");
+        let mut patch = b"UPS1".to_vec();
+        varint(4, &mut patch);
+        varint(5, &mut patch);
+        varint(2, &mut patch);
+        patch.extend([b'C' ^ b'X', 0]);
+        varint(0, &mut patch);
+        patch.extend([b'E', 0]);
+        let patch = with_footer(patch, source, target);
+
+        assert_eq!(apply(source, &patch).unwrap(), target);
+        assert!(matches!(
+            apply(b"ABCE", &patch),
+            Err(PatchError::SourceMismatch { .. })
+        ));
+
+        // Expanding to 64K costs nothing for the zero-filled tail
+        let mut target = source.to_vec();
+        target.resize(0x10000, 0);
+        let mut patch = b"UPS1".to_vec();
+        varint(4, &mut patch);
+        varint(target.len(), &mut patch);
+        let patch = with_footer(patch, source, &target);
+        assert_eq!(apply(source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn test_bps() {
+        let (source, target) = (b"hello", b"hellO!hellO!");
+        let mut patch = b"BPS1".to_vec();
+        varint(5, &mut patch);
+        varint(12, &mut patch);
+        varint(0, &mut patch);
+        // SourceRead 4, TargetRead "O!", TargetCopy 6 from 0
+        varint(3 << 2, &mut patch);
+        varint((1 << 2) | 1, &mut patch);
+        patch.extend(b"O!");
+        varint((5 << 2) | 3, &mut patch);
+        varint(0, &mut patch);
+        let patch = with_footer(patch, source, target);
+
+        assert_eq!(apply(source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn test_target_too_large() {
+        let source = b"ABCD";
+        let target_size = MAX_TARGET_SIZE + 1;
+        for magic in [b"UPS1", b"BPS1"] {
+            let mut patch = magic.to_vec();
+            varint(4, &mut patch);
+            varint(target_size, &mut patch);
+            varint(0, &mut patch);
+            let patch = with_footer(patch, source, b"");
+
+            assert!(matches!(
+                apply(source, &patch),
+                Err(PatchError::TargetTooLarge(size)) if size == target_size
+            ));
+        }
+    }
+
+    #[test]
+    fn test_find_patch_next_to_archive() {
+        let dir = std::env::temp_dir();
+        let patch = dir.join(format!("nesboy-{}-game.ips", std::process::id()));
+        std::fs::write(&patch, b"PATCHEOF").unwrap();
+
+        for rom in ["game.nes", "game.zip", "game.nes.gz"] {
+            let rom = dir.join(format!("nesboy-{}-{}", std::process::id(), rom));
+            assert_eq!(
+                find_patch(rom.to_str().unwrap()),
+                Some(patch.clone()),
+                "{:?}",
+                rom
+            );
+        }
+        std::fs::remove_file(patch).unwrap();
+    }
+}
@@ -1,179 +1,570 @@
-use crate::add_register::AddrRegister;
 use crate::controller_register::ControlRegister;
+use crate::loopy_register::LoopyRegister;
+use crate::mask_register::MaskRegister;
+use crate::region::Region;
+use crate::status_register::StatusRegister;
 
+const MAX_SPRITES_PER_LINE: usize = 8;
 
-#[derive(Debug)]
+// How the four logical nametables at $2000-$2FFF map onto nametable RAM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
+    SINGLE_SCREEN_A, // all four show the first 1K of CIRAM
+    SINGLE_SCREEN_B, // all four show the second 1K of CIRAM
+    FOUR_SCREEN,     // 4K of cartridge VRAM, one table each
 }
 
 pub struct Ppu {
     // PPU Registers
-    pub control: u8,
-    pub mask: u8,
-    pub status: u8,
+    pub mask: MaskRegister,
+    pub status: StatusRegister,
     pub oam_addr: u8,
-    pub oam_data: u8,
-    pub scroll: u8,
-    // pub addr: u8,
-    pub data: u8,
-    pub oam_dma: u8,
+    pub oam_data: [u8; 256],
 
     internal_data_buf: u8,
+    open_bus: u8,       // last value driven onto the CPU-PPU data bus
+
+    // Scrolling: current and temporary VRAM address, fine X, write toggle
+    v: LoopyRegister,
+    t: LoopyRegister,
+    fine_x: u8,
+    write_toggle: bool, // w: $2005/$2006 second-write latch
 
     pub ctrl: ControlRegister,
 
     // Internal state
     chr_rom: Vec<u8>,
-    framebuffer: [u8; 256 * 240],
-    vram: [u8; 0x4000],
-    name_table: [u8; 1024],
-    palette_table: [u8; 64],
+    chr_ram: bool, // pattern tables are writable through $2007
+    chr_banks: [usize; 2], // offsets of the $0000 and $1000 pattern tables into CHR
+    framebuffer: [u16; 256 * 240], // colour index, emphasis bits (R, G, B) in bits 6-8
+    region: Region,
+    ciram: [u8; 0x800],        // the console's 2K of nametable RAM
+    four_screen_vram: Vec<u8>, // extra cartridge VRAM, only for four-screen boards
+    palette_table: [u8; 32],
 
     // Rendering state
     scanline: i32,
     cycle: i32,
     frame_complete: bool,
-    addr: AddrRegister,
+    odd_frame: bool,
+    nmi_pending: bool,     // rising edge of vblank && GENERATE_NMI, taken by the CPU
+    nmi_delay: bool,       // raised by a $2000 write, the CPU runs one more instruction first
+    suppress_vblank: bool, // $2002 was read just before vblank started
 
     mirroring: Mirroring,
 
+    // Background latches for the next tile, fed into the shifters every 8 dots
     tile_id: u8,
     tile_attrib: u8,
     tile_lsb: u8,
     tile_msb: u8,
+    bg_pattern_lo: u16,
+    bg_pattern_hi: u16,
+    bg_attrib_lo: u16,
+    bg_attrib_hi: u16,
+
+    // Sprites: secondary OAM is filled for the next line at dot 256, its
+    // patterns are fetched during dots 257-320 into the per-slot latches
+    secondary_oam: [u8; 32],
+    secondary_count: usize,
+    sprite_zero_next: bool, // OAM sprite 0 made it into secondary OAM
+    sprite_count: usize,
+    sprite_zero_in_line: bool, // slot 0 holds OAM sprite 0
+    sprite_x: [u8; MAX_SPRITES_PER_LINE],
+    sprite_attrib: [u8; MAX_SPRITES_PER_LINE],
+    sprite_pattern_lo: [u8; MAX_SPRITES_PER_LINE],
+    sprite_pattern_hi: [u8; MAX_SPRITES_PER_LINE],
+}
+
+// Palette RAM is 32 bytes mirrored through $3F00-$3FFF. The backdrop entries
+// of the sprite palettes ($3F10/$3F14/$3F18/$3F1C) alias the background ones.
+fn palette_index(addr: u16) -> usize {
+    let index = addr as usize & 0x1F;
+    if index & 0x13 == 0x10 {
+        index & 0x0F
+    } else {
+        index
+    }
 }
 
 impl Ppu {
-    pub fn new(mirroring: bool, chr_rom: Vec<u8>) -> Self {
+    pub fn new(mirroring: Mirroring, chr_rom: Vec<u8>, chr_ram: bool) -> Self {
         Ppu {
-            control: 0,
-            mask: 0,
-            status: 0,
+            mask: MaskRegister::new(),
+            status: StatusRegister::new(),
             oam_addr: 0,
-            oam_data: 0,
-            scroll: 0,
-            // addr: 0,
-            data: 0,
-            oam_dma: 0,
+            oam_data: [0; 256],
             framebuffer: [0; 256 * 240],
-            vram: [0; 0x4000],
-            name_table: [0; 1024],
-            palette_table: [0; 64],
+            region: Region::Ntsc,
+            ciram: [0; 0x800],
+            four_screen_vram: if mirroring == Mirroring::FOUR_SCREEN {
+                vec![0; 0x1000]
+            } else {
+                Vec::new()
+            },
+            palette_table: [0; 32],
             scanline: 0,
             cycle: 0,
             frame_complete: false,
+            odd_frame: false,
+            nmi_pending: false,
+            nmi_delay: false,
+            suppress_vblank: false,
             tile_id: 0,
             tile_attrib: 0,
             tile_lsb: 0,
             tile_msb: 0,
+            bg_pattern_lo: 0,
+            bg_pattern_hi: 0,
+            bg_attrib_lo: 0,
+            bg_attrib_hi: 0,
+            secondary_oam: [0xFF; 32],
+            secondary_count: 0,
+            sprite_zero_next: false,
+            sprite_count: 0,
+            sprite_zero_in_line: false,
+            sprite_x: [0; MAX_SPRITES_PER_LINE],
+            sprite_attrib: [0; MAX_SPRITES_PER_LINE],
+            sprite_pattern_lo: [0; MAX_SPRITES_PER_LINE],
+            sprite_pattern_hi: [0; MAX_SPRITES_PER_LINE],
 
-            mirroring: if mirroring {
-                Mirroring::VERTICAL
-            } else {
-                Mirroring::HORIZONTAL
-            },
+            mirroring,
             chr_rom,
+            chr_ram,
+            chr_banks: [0, 0x1000],
             ctrl: ControlRegister::new(),
-            addr: AddrRegister::new(),
             internal_data_buf: 0,
+            open_bus: 0,
+            v: LoopyRegister::new(),
+            t: LoopyRegister::new(),
+            fine_x: 0,
+            write_toggle: false,
         }
     }
 
-    pub fn get_frame(&self) -> &[u8; 256 * 240] {
+    pub fn get_frame(&self) -> &[u16; 256 * 240] {
         &self.framebuffer
     }
 
+    /// Frame length, vblank position and 2C02/2C07 differences
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    fn pre_render_scanline(&self) -> i32 {
+        self.region.scanlines() - 1
+    }
+
+    // t: ...GH.. ........ <- d: ......GH
     fn write_to_ctrl(&mut self, value: u8) {
-       self.ctrl.update(value);
+        let nmi_was_enabled = self.ctrl.generate_vblank_nmi();
+        self.ctrl.update(value);
+        self.t.set_nametable(value);
+
+        // Enabling NMI while the vblank flag is still set fires after the next
+        // instruction; disabling it right as vblank starts drops the NMI
+        let nmi_enabled = self.ctrl.generate_vblank_nmi();
+        if !nmi_was_enabled && nmi_enabled && self.status.is_in_vblank() {
+            self.nmi_pending = true;
+            self.nmi_delay = true;
+        } else if !nmi_enabled
+            && self.scanline == self.region.vblank_scanline()
+            && self.cycle <= 3
+        {
+            self.nmi_pending = false;
+        }
+    }
+
+    fn is_rendering(&self) -> bool {
+        self.mask.rendering_enabled()
+            && (self.scanline < 240 || self.scanline == self.pre_render_scanline())
     }
 
     fn increment_vram_addr(&mut self) {
-       self.addr.increment(self.ctrl.vram_addr_increment());
+        if self.is_rendering() {
+            // $2007 access mid-render bumps both scroll counters instead
+            self.v.increment_x();
+            self.v.increment_y();
+        } else {
+            self.v.increment(self.ctrl.vram_addr_increment());
+        }
     }
 
     pub fn read_data(&mut self) -> u8 {
-        let addr = self.addr.get();
+        let addr = self.v.get() & 0x3fff;
         self.increment_vram_addr();
 
-       match addr {
+        match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.chr_rom[self.chr_index(addr)];
                 result
             }
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                self.internal_data_buf = self.read_nametable(addr);
                 result
             }
-           0x3000..=0x3eff => panic!("addr space 0x3000..0x3eff is not expected to be used, requested = {} ", addr),
-           0x3f00..=0x3fff =>
-           {
-               self.palette_table[(addr - 0x3f00) as usize]
-           }
-           _ => panic!("unexpected access to mirrored space {}", addr),
-       }
-    }
-
-    // pub fn read(&mut self, addr: u16) -> u8 {
-    //     // Read from PPU memory or registers
-    //     match addr {
-    //         0x2000 => self.control,
-    //         0x2001 => self.mask,
-    //         0x2002 => self.read_status(addr),
-    //         0x2004 => self.oam_data,
-    //         0x2007 => self.data,
-    //         _ => 0,
-    //     }
-    // }
-
-    // pub fn write(&mut self, addr: u16, data: u8) {
-    //     // Write to PPU memory or registers
-    //     match addr {
-    //         0x2000 => self.write_control(addr, data),
-    //         0x2001 => self.write_mask(addr, data),
-    //         0x2003 => self.oam_addr = data,
-    //         0x2004 => self.write_oam(addr, data),
-    //         0x2005 => self.scroll = data,
-    //         0x2006 => self.addr = data,
-    //         0x2007 => self.data = data,
-    //         _ => {}
-    //     }
-    // }
+            0x3f00..=0x3fff => {
+                // Palette reads skip the buffer, which picks up the nametable
+                // byte underneath instead ($2F00-$2FFF). Palette RAM is 6 bits
+                // wide, the top two come from open bus.
+                self.internal_data_buf = self.read_nametable(addr);
+                self.read_palette(addr) | (self.open_bus & 0xC0)
+            }
+            _ => unreachable!("PPU address {:#06x} is mirrored down to 0x3fff", addr),
+        }
+    }
+
+    /// CPU read of a PPU register, `addr` already mirrored down to $2000-$2007
+    pub fn read(&mut self, addr: u16) -> u8 {
+        let value = match addr {
+            0x2002 => self.read_status(addr),
+            0x2004 => self.oam_data[self.oam_addr as usize],
+            0x2007 => self.read_data(),
+            // Write-only registers return whatever is left on the bus
+            _ => self.open_bus,
+        };
+        self.open_bus = value;
+        value
+    }
+
+    /// CPU write to a PPU register, `addr` already mirrored down to $2000-$2007
+    pub fn write(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
+        match addr {
+            0x2000 => self.write_to_ctrl(data),
+            0x2001 => self.write_mask(addr, data),
+            0x2002 => {} // read-only
+            0x2003 => self.oam_addr = data,
+            0x2004 => self.write_oam(addr, data),
+            0x2005 => self.write_scroll(data),
+            0x2006 => self.write_to_ppu_addr(data),
+            0x2007 => self.write_data(data),
+            _ => unreachable!("PPU register {:#06x} is not mirrored down", addr),
+        }
+    }
+
+    fn write_data(&mut self, data: u8) {
+        let addr = self.v.get() & 0x3fff;
+        match addr {
+            0..=0x1fff => {
+                if self.chr_ram {
+                    let index = self.chr_index(addr);
+                    self.chr_rom[index] = data;
+                }
+            }
+            0x2000..=0x3eff => {
+                self.write_nametable(addr, data);
+            }
+            0x3f00..=0x3fff => {
+                self.palette_table[palette_index(addr)] = data & 0x3F;
+            }
+            _ => unreachable!("PPU address {:#06x} is mirrored down to 0x3fff", addr),
+        }
+        self.increment_vram_addr();
+    }
 
     pub fn step(&mut self) {
-        // Perform one PPU cycle
+        if self.scanline == self.region.vblank_scanline() && self.cycle == 1 {
+            if !self.suppress_vblank {
+                self.status.set_vblank_status(true);
+                if self.ctrl.generate_vblank_nmi() {
+                    self.nmi_pending = true;
+                }
+            }
+            self.suppress_vblank = false;
+        }
+        if self.scanline == self.pre_render_scanline() && self.cycle == 1 {
+            self.status.reset_vblank_status();
+            self.status.set_sprite_zero_hit(false);
+            self.status.set_sprite_overflow(false);
+        }
+        if self.is_rendering() {
+            self.fetch_background();
+            self.fetch_sprites();
+            self.update_scroll();
+        }
+        if self.scanline < 240 && (1..=256).contains(&self.cycle) {
+            self.render_pixel();
+        }
+
+        // Perform one PPU cycle. With rendering on, odd NTSC frames skip the
+        // last dot of the pre-render line.
         self.cycle += 1;
+        if self.scanline == self.pre_render_scanline()
+            && self.cycle == 340
+            && self.odd_frame
+            && self.mask.rendering_enabled()
+            && self.region.skips_odd_frame_dot()
+        {
+            self.cycle = 341;
+        }
         if self.cycle > 340 {
             self.cycle = 0;
             self.scanline += 1;
-            if self.scanline > 261 {
+            if self.scanline > self.pre_render_scanline() {
                 self.scanline = 0;
                 self.frame_complete = true;
+                self.odd_frame = !self.odd_frame;
             }
         }
     }
 
-    pub fn render_tile(&mut self) {
-        let tileX = (self.cycle - 1) / 8;
-        let tileY = self.scanline / 8;
-        let pixelRow = self.scanline % 8;
+    // v advances with the fetches: coarse X after every tile, fine/coarse Y at
+    // the end of the visible dots, then the scroll position is reloaded from t
+    fn update_scroll(&mut self) {
+        match self.cycle {
+            8..=256 | 328 | 336 if self.cycle % 8 == 0 => {
+                self.v.increment_x();
+                if self.cycle == 256 {
+                    self.v.increment_y();
+                }
+            }
+            257 => self.v.copy_horizontal(&self.t),
+            280..=304 if self.scanline == self.pre_render_scanline() => {
+                self.v.copy_vertical(&self.t)
+            }
+            _ => {}
+        }
+    }
 
-        for i in 0..8 {
-            // Bits from pattern table
-            let bit0: u8 = (self.tile_lsb >> (7 - i)) & 1;
-            let bit1: u8 = (self.tile_msb >> (7 - i)) & 1;
+    // Background fetches repeat every 8 dots: nametable byte, attribute byte,
+    // pattern low, pattern high. Dots 321-336 prefetch the next line's first
+    // two tiles.
+    fn fetch_background(&mut self) {
+        if matches!(self.cycle, 2..=257 | 322..=337) {
+            self.shift_background();
+        }
+        if matches!(self.cycle, 1..=256 | 321..=336) {
+            match (self.cycle - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    self.fetch_tile_id();
+                }
+                2 => self.fetch_attribute(),
+                4 => self.fetch_tile_lsb(),
+                6 => self.fetch_tile_msb(),
+                _ => {}
+            }
+        } else if self.cycle == 257 {
+            self.load_background_shifters();
+        }
+    }
 
-            let colorIndex = (bit1 << 1) | bit0; // Combine to 2-bit color index
+    fn shift_background(&mut self) {
+        self.bg_pattern_lo <<= 1;
+        self.bg_pattern_hi <<= 1;
+        self.bg_attrib_lo <<= 1;
+        self.bg_attrib_hi <<= 1;
+    }
 
-            let x = tileX * 8 + i;
-            let y = self.scanline;
+    // The fetched tile goes into the low byte, the attribute bits are spread
+    // over all 8 pixels
+    fn load_background_shifters(&mut self) {
+        self.bg_pattern_lo = (self.bg_pattern_lo & 0xFF00) | self.tile_lsb as u16;
+        self.bg_pattern_hi = (self.bg_pattern_hi & 0xFF00) | self.tile_msb as u16;
+        let attrib_lo = if self.tile_attrib & 0b01 != 0 { 0xFF } else { 0x00 };
+        let attrib_hi = if self.tile_attrib & 0b10 != 0 { 0xFF } else { 0x00 };
+        self.bg_attrib_lo = (self.bg_attrib_lo & 0xFF00) | attrib_lo;
+        self.bg_attrib_hi = (self.bg_attrib_hi & 0xFF00) | attrib_hi;
+    }
 
-            if (x < 256 && y < 240) {
-                self.framebuffer[y as usize * 256 + x as usize] = colorIndex; // Save to framebuffer
+    fn fetch_sprites(&mut self) {
+        // OAMADDR is held at 0 while the sprite patterns are fetched
+        if (257..=320).contains(&self.cycle) {
+            self.oam_addr = 0;
+        }
+        match self.cycle {
+            1 => self.secondary_oam.fill(0xFF),
+            256 if self.scanline == self.pre_render_scanline() => {
+                self.secondary_count = 0;
+                self.sprite_zero_next = false;
+            }
+            256 => self.evaluate_sprites(),
+            257..=320 if (self.cycle - 257) % 8 == 7 => {
+                let slot = ((self.cycle - 257) / 8) as usize;
+                self.fetch_sprite_pattern(slot);
             }
+            _ => {}
+        }
+    }
+
+    // Copy the first 8 sprites in range of the next line into secondary OAM
+    fn evaluate_sprites(&mut self) {
+        let height = self.ctrl.sprite_size() as i32;
+        let scanline = self.scanline;
+        let in_range = |y: u8| (0..height).contains(&(scanline - y as i32));
+
+        self.secondary_count = 0;
+        self.sprite_zero_next = false;
+        let mut n = 0;
+        while n < 64 && self.secondary_count < MAX_SPRITES_PER_LINE {
+            let sprite = &self.oam_data[n * 4..n * 4 + 4];
+            if in_range(sprite[0]) {
+                let slot = self.secondary_count * 4;
+                self.secondary_oam[slot..slot + 4].copy_from_slice(sprite);
+                self.secondary_count += 1;
+                self.sprite_zero_next |= n == 0;
+            }
+            n += 1;
+        }
+
+        // Overflow search: the hardware increments the byte offset along with
+        // the sprite index, so it checks tile/attribute/X bytes as Y values
+        // and both misses real overflows and reports false ones
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam_data[n * 4 + m]) {
+                self.status.set_sprite_overflow(true);
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0x03;
+        }
+    }
+
+    fn fetch_sprite_pattern(&mut self, slot: usize) {
+        if slot == 0 {
+            self.sprite_count = self.secondary_count;
+            self.sprite_zero_in_line = self.sprite_zero_next;
+        }
+        if slot >= self.sprite_count {
+            self.sprite_pattern_lo[slot] = 0;
+            self.sprite_pattern_hi[slot] = 0;
+            return;
+        }
+
+        let sprite = &self.secondary_oam[slot * 4..slot * 4 + 4];
+        let (y, tile, attrib, x) = (sprite[0], sprite[1], sprite[2], sprite[3]);
+        let height = self.ctrl.sprite_size() as u16;
+
+        let mut row = (self.scanline - y as i32) as u16;
+        if attrib & 0x80 != 0 {
+            row = height - 1 - row; // vertical flip
+        }
+
+        // 8x16 sprites take their pattern table from bit 0 of the tile index
+        let addr = if height == 16 {
+            let table = (tile as u16 & 0x01) * 0x1000;
+            let tile = (tile & 0xFE) as u16 + row / 8;
+            table + tile * 16 + row % 8
+        } else {
+            self.ctrl.sprt_pattern_addr() + tile as u16 * 16 + row
+        };
+
+        let mut lo = self.ppu_read(addr);
+        let mut hi = self.ppu_read(addr + 8);
+        if attrib & 0x40 != 0 {
+            lo = lo.reverse_bits(); // horizontal flip
+            hi = hi.reverse_bits();
+        }
+        self.sprite_x[slot] = x;
+        self.sprite_attrib[slot] = attrib;
+        self.sprite_pattern_lo[slot] = lo;
+        self.sprite_pattern_hi[slot] = hi;
+    }
+
+    // Fine X picks the bit out of the top of the shifters; a transparent
+    // pixel (or rendering off) shows the backdrop colour
+    fn render_pixel(&mut self) {
+        let x = (self.cycle - 1) as usize;
+        let y = self.scanline as usize;
+
+        // Either layer can be hidden in the leftmost 8 pixels
+        let mut bg_pixel = 0;
+        let mut bg_palette = 0;
+        if self.mask.show_background() && (x >= 8 || self.mask.leftmost_8pxl_background()) {
+            let mux = 0x8000 >> self.fine_x;
+            let bit = |shifter: u16| (shifter & mux != 0) as u8;
+            bg_pixel = (bit(self.bg_pattern_hi) << 1) | bit(self.bg_pattern_lo);
+            bg_palette = (bit(self.bg_attrib_hi) << 1) | bit(self.bg_attrib_lo);
+        }
+
+        let show_sprites =
+            self.mask.show_sprites() && (x >= 8 || self.mask.leftmost_8pxl_sprite());
+        let (sprite_pixel, sprite_palette, behind_background, slot) = if show_sprites {
+            self.sprite_pixel(x)
+        } else {
+            (0, 0, false, 0)
+        };
+
+        // Sprite 0 hit: opaque sprite 0 over opaque background, except at
+        // x=255 (clipped pixels are already transparent)
+        if self.sprite_zero_in_line && slot == 0 && bg_pixel != 0 && sprite_pixel != 0 && x != 255
+        {
+            self.status.set_sprite_zero_hit(true);
+        }
+
+        let (palette, pixel) = match (bg_pixel, sprite_pixel) {
+            (_, 0) => (bg_palette, bg_pixel),
+            (0, _) => (sprite_palette, sprite_pixel),
+            _ if behind_background => (bg_palette, bg_pixel),
+            _ => (sprite_palette, sprite_pixel),
+        };
+        let color = self.palette_color(palette, pixel) as u16;
+        self.framebuffer[y * 256 + x] = color | ((self.emphasis() as u16) << 6);
+    }
+
+    fn emphasis(&self) -> u8 {
+        let emphasis = self.mask.emphasis();
+        if self.region.swaps_red_green_emphasis() {
+            (emphasis & 0b100) | ((emphasis & 0b001) << 1) | ((emphasis & 0b010) >> 1)
+        } else {
+            emphasis
+        }
+    }
+
+    /// First opaque sprite pixel at `x` in OAM order:
+    /// (pixel, palette, behind background, slot)
+    fn sprite_pixel(&self, x: usize) -> (u8, u8, bool, usize) {
+        for slot in 0..self.sprite_count {
+            let column = x.wrapping_sub(self.sprite_x[slot] as usize);
+            if column >= 8 {
+                continue;
+            }
+            let bit = |pattern: u8| (pattern >> (7 - column)) & 1;
+            let pixel =
+                (bit(self.sprite_pattern_hi[slot]) << 1) | bit(self.sprite_pattern_lo[slot]);
+            if pixel != 0 {
+                let attrib = self.sprite_attrib[slot];
+                return (pixel, 4 + (attrib & 0x03), attrib & 0x20 != 0, slot);
+            }
+        }
+        (0, 0, false, 0)
+    }
+
+    /// Colour index (0-63) from palette RAM for a 2-bit pixel of a palette
+    fn palette_color(&self, palette: u8, pixel: u8) -> u8 {
+        let index = if pixel == 0 { 0 } else { (palette << 2) | pixel };
+        self.read_palette(index as u16)
+    }
+
+    // Greyscale keeps only the brightness column of the colour
+    fn read_palette(&self, addr: u16) -> u8 {
+        let color = self.palette_table[palette_index(addr)];
+        if self.mask.is_greyscale() {
+            color & 0x30
+        } else {
+            color
+        }
+    }
+
+    // NES 2.0 allows CHR smaller than 8KB, which mirrors through $0000-$1FFF
+    fn chr_index(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize >> 12];
+        (bank + (addr as usize & 0x0FFF)) % self.chr_rom.len()
+    }
+
+    /// PPU-side memory read used by the rendering fetches
+    fn ppu_read(&self, addr: u16) -> u8 {
+        match addr & 0x3fff {
+            addr @ 0..=0x1fff => self.chr_rom[self.chr_index(addr)],
+            addr @ 0x2000..=0x3eff => self.read_nametable(addr),
+            addr => self.read_palette(addr),
         }
     }
 
@@ -184,21 +575,85 @@ impl Ppu {
     // Vertical:
     //   [ A ] [ B ]
     //   [ a ] [ b ]
+
+    // Single-screen:
+    //   [ A ] [ a ]
+    //   [ a ] [ a ]
+    //
+    // Returns an offset into CIRAM, or into the cartridge VRAM for four-screen
     pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
-        let mirrored_vram = addr & 0b10111111111111; // mirror down 0x3000-0x3eff to 0x2000 - 0x2eff
-        let vram_index = mirrored_vram - 0x2000; // to vram vector
+        let vram_index = (addr - 0x2000) & 0x0fff; // mirror down 0x3000-0x3eff to 0x2000-0x2eff
         let name_table = vram_index / 0x400;
-        match (&self.mirroring, name_table) {
-            (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
-            (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
-            _ => vram_index,
+        let bank = match self.mirroring {
+            Mirroring::VERTICAL => name_table & 1,
+            Mirroring::HORIZONTAL => name_table >> 1,
+            Mirroring::SINGLE_SCREEN_A => 0,
+            Mirroring::SINGLE_SCREEN_B => 1,
+            Mirroring::FOUR_SCREEN => name_table,
+        };
+        bank * 0x400 + (vram_index & 0x3ff)
+    }
+
+    fn read_nametable(&self, addr: u16) -> u8 {
+        let index = self.mirror_vram_addr(addr) as usize;
+        match self.mirroring {
+            Mirroring::FOUR_SCREEN => self.four_screen_vram[index],
+            _ => self.ciram[index],
         }
     }
 
+    fn write_nametable(&mut self, addr: u16, data: u8) {
+        let index = self.mirror_vram_addr(addr) as usize;
+        match self.mirroring {
+            Mirroring::FOUR_SCREEN => self.four_screen_vram[index] = data,
+            _ => self.ciram[index] = data,
+        }
+    }
+
+    // w=0: t: .CDEFGH ........ <- d: ..CDEFGH (bit 14 cleared)
+    // w=1: t: ....... ABCDEFGH <- d: ABCDEFGH, then v = t
     fn write_to_ppu_addr(&mut self, value: u8) {
-       self.addr.update(value);
+        if !self.write_toggle {
+            self.t.set_high_byte(value);
+        } else {
+            self.t.set_low_byte(value);
+            self.v = self.t;
+        }
+        self.write_toggle = !self.write_toggle;
+    }
+
+    // w=0: t: ....... ...ABCDE <- d: ABCDE..., x <- d: .....FGH
+    // w=1: t: FGH..AB CDE..... <- d: ABCDEFGH
+    fn write_scroll(&mut self, value: u8) {
+        if !self.write_toggle {
+            self.t.set_coarse_x(value >> 3);
+            self.fine_x = value & 0x07;
+        } else {
+            self.t.set_coarse_y(value >> 3);
+            self.t.set_fine_y(value & 0x07);
+        }
+        self.write_toggle = !self.write_toggle;
+    }
+
+    /// Mapper-controlled mirroring, may change at any time
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        if mirroring == Mirroring::FOUR_SCREEN && self.four_screen_vram.is_empty() {
+            self.four_screen_vram = vec![0; 0x1000];
+        }
+        self.mirroring = mirroring;
+    }
+
+    /// Mapper CHR banking: where in CHR each 4K pattern table starts
+    pub fn set_chr_banks(&mut self, banks: [usize; 2]) {
+        self.chr_banks = banks;
+    }
+
+    /// Take the pending NMI, if any. Called once per CPU instruction.
+    pub fn poll_nmi(&mut self) -> bool {
+        if std::mem::take(&mut self.nmi_delay) {
+            return false;
+        }
+        std::mem::take(&mut self.nmi_pending)
     }
 
     pub fn is_frame_complete(&self) -> bool {
@@ -209,48 +664,600 @@ impl Ppu {
         self.frame_complete = false;
     }
 
+    // Only the top three bits are driven, the rest is open bus. Reading
+    // clears vblank and the $2005/$2006 write toggle.
+    //
+    // Race with vblank start: a read just before dot 1 of line 241 sees the
+    // flag clear and keeps it (and the NMI) from being set this frame; a read
+    // on the following dots sees it set but still cancels the NMI.
     fn read_status(&mut self, _addr: u16) -> u8 {
-        self.status
+        if self.scanline == self.region.vblank_scanline() {
+            match self.cycle {
+                1 => self.suppress_vblank = true,
+                2 | 3 => self.nmi_pending = false,
+                _ => {}
+            }
+        }
+        let data = (self.status.snapshot() & 0xE0) | (self.open_bus & 0x1F);
+        self.status.reset_vblank_status();
+        self.write_toggle = false;
+        data
     }
 
-    fn write_control(&mut self, _addr: u16, data: u8) {
-        self.control = data;
+    fn write_mask(&mut self, _addr: u16, data: u8) {
+        self.mask.update(data);
     }
 
-    fn write_mask(&mut self, _addr: u16, data: u8) {
-        self.mask = data;
+    /// OAM DMA: 256 bytes written through OAMDATA, starting at OAMADDR
+    pub fn write_oam_dma(&mut self, data: &[u8; 256]) {
+        for &byte in data.iter() {
+            self.write_oam(0x2004, byte);
+        }
     }
 
     fn write_oam(&mut self, _addr: u16, data: u8) {
-        self.oam_data = data;
+        // Bits 2-4 of the attribute byte don't exist and read back as 0
+        let data = if self.oam_addr & 0x03 == 2 { data & 0xE3 } else { data };
+        self.oam_data[self.oam_addr as usize] = data;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
     }
 
     fn fetch_tile_id(&mut self) {
         // Fetch tile ID from name table
+        self.tile_id = self.ppu_read(0x2000 | (self.v.get() & 0x0FFF));
     }
 
     fn fetch_attribute(&mut self) {
-        // Fetch attribute byte
+        // Fetch attribute byte: one byte per 4x4 tiles, 2 bits per 2x2 quadrant
+        let v = self.v.get();
+        let addr = 0x23C0
+            | (v & 0x0C00)
+            | ((self.v.coarse_y() >> 2) << 3)
+            | (self.v.coarse_x() >> 2);
+        let mut attrib = self.ppu_read(addr);
+        if self.v.coarse_y() & 0x02 != 0 {
+            attrib >>= 4;
+        }
+        if self.v.coarse_x() & 0x02 != 0 {
+            attrib >>= 2;
+        }
+        self.tile_attrib = attrib & 0x03;
+    }
+
+    fn background_pattern_addr(&self) -> u16 {
+        self.ctrl.bknd_pattern_addr() + self.tile_id as u16 * 16 + self.v.fine_y()
     }
 
     fn fetch_tile_lsb(&mut self) {
         // Fetch tile LSB
+        self.tile_lsb = self.ppu_read(self.background_pattern_addr());
     }
 
     fn fetch_tile_msb(&mut self) {
         // Fetch tile MSB
+        self.tile_msb = self.ppu_read(self.background_pattern_addr() + 8);
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // NTSC frame layout
+    const PRE_RENDER_SCANLINE: i32 = 261;
+    const VBLANK_SCANLINE: i32 = 241;
+
+    #[test]
+    fn test_status_read_clears_vblank_and_latch() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], true);
+        ppu.status.set_vblank_status(true);
+        ppu.write_to_ppu_addr(0x23);
+
+        assert_eq!(ppu.read(0x2002) & 0x80, 0x80);
+        assert!(!ppu.status.is_in_vblank());
+        assert_eq!(ppu.read(0x2002) & 0x80, 0);
+
+        // The latch is back on the high byte
+        ppu.write_to_ppu_addr(0x21);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.v.get(), 0x2105);
+    }
+
+    #[test]
+    fn test_ppudata_read_is_buffered() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], true);
+        ppu.ciram[0x0305] = 0x66;
+        ppu.ciram[0x0306] = 0x77;
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+
+        ppu.read(0x2007); // stale buffer
+        assert_eq!(ppu.read(0x2007), 0x66);
+        assert_eq!(ppu.read(0x2007), 0x77);
+    }
+
+    #[test]
+    fn test_write_only_registers_read_open_bus() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], true);
+        ppu.oam_addr = 0x10;
+        ppu.oam_data[0x10] = 0xA5;
+        assert_eq!(ppu.read(0x2004), 0xA5);
+        assert_eq!(ppu.read(0x2000), 0xA5);
+        assert_eq!(ppu.read(0x2002), 0x05);
+    }
+
+    #[test]
+    fn test_scroll_and_addr_share_write_toggle() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], true);
+        ppu.write(0x2005, 0x7D); // first write: X
+        ppu.write(0x2006, 0x05); // second write: low address byte
+        assert_eq!(ppu.t.coarse_x(), 0x05);
+        assert_eq!(ppu.fine_x, 0x05);
+        assert_eq!(ppu.v.get(), 0x0005);
+
+        ppu.read(0x2002);
+        ppu.write(0x2006, 0x21);
+        ppu.write(0x2006, 0x08);
+        ppu.write(0x2007, 0x42);
+        assert_eq!(ppu.ciram[0x0108], 0x42);
+        assert_eq!(ppu.v.get(), 0x2109);
+    }
+
+    // Example sequence from the nesdev wiki scrolling article
+    #[test]
+    fn test_loopy_register_updates() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], true);
+        ppu.write(0x2000, 0x00);
+        ppu.read(0x2002);
+        ppu.write(0x2005, 0x7D);
+        assert_eq!((ppu.t.get(), ppu.fine_x), (0x000F, 0x05));
+        ppu.write(0x2005, 0x5E);
+        assert_eq!(ppu.t.get(), 0x616F);
+        ppu.write(0x2006, 0x3D);
+        assert_eq!(ppu.t.get(), 0x3D6F);
+        ppu.write(0x2006, 0xF0);
+        assert_eq!(ppu.t.get(), 0x3DF0);
+        assert_eq!(ppu.v.get(), 0x3DF0);
+
+        ppu.write(0x2000, 0x03);
+        assert_eq!(ppu.t.get(), 0x3DF0 | 0x0C00);
+    }
+
+    #[test]
+    fn test_scroll_copied_into_v_while_rendering() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], true);
+        ppu.write(0x2001, 0x08);
+        ppu.write(0x2005, 0x08); // coarse X = 1
+        ppu.write(0x2005, 0x10); // coarse Y = 2
+
+        // Pre-render line reloads vertical bits, dot 257 the horizontal ones
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        ppu.cycle = 280;
+        ppu.step();
+        assert_eq!(ppu.v.coarse_y(), 2);
+        ppu.scanline = 0;
+        ppu.cycle = 257;
+        ppu.step();
+        assert_eq!(ppu.v.coarse_x(), 1);
+
+        // One tile fetched at dot 8
+        ppu.cycle = 8;
+        ppu.step();
+        assert_eq!(ppu.v.coarse_x(), 2);
+    }
+
+    #[test]
+    fn test_oam_and_chr_writes() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], false);
+        ppu.write(0x2003, 0x02);
+        ppu.write(0x2004, 0xFF);
+        ppu.write(0x2004, 0x10);
+        assert_eq!(ppu.oam_data[2], 0xE3);
+        assert_eq!(ppu.oam_data[3], 0x10);
+        assert_eq!(ppu.oam_addr, 4);
+
+        // CHR-ROM ignores writes
+        ppu.write(0x2006, 0x00);
+        ppu.write(0x2006, 0x10);
+        ppu.write(0x2007, 0x99);
+        assert_eq!(ppu.chr_rom[0x10], 0);
+    }
+
+    #[test]
+    fn test_small_chr_is_mirrored() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x800], true);
+        ppu.write(0x2006, 0x18);
+        ppu.write(0x2006, 0x05);
+        ppu.write(0x2007, 0x5A);
+        assert_eq!(ppu.chr_rom[0x05], 0x5A);
+        assert_eq!(ppu.ppu_read(0x0805), 0x5A);
+    }
+
+    #[test]
+    fn test_background_rendering() {
+        // Tile 1 is solid colour 1, tile 0 is transparent
+        let mut chr = vec![0; 0x2000];
+        chr[16..24].fill(0xFF);
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, chr, false);
+        ppu.ciram[1..0x3C0].fill(1);
+        ppu.ciram[0x3C0] = 0b01; // top-left quadrant uses palette 1
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[5] = 0x16;
+        ppu.write(0x2001, 0x0A);
+        ppu.write(0x2005, 3); // fine X = 3
+        ppu.write(0x2005, 0);
+
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        while !ppu.is_frame_complete() {
+            ppu.step();
+        }
+        for _ in 0..341 * 8 {
+            ppu.step();
+        }
+
+        let frame = ppu.get_frame();
+        for row in 0..8 {
+            assert_eq!(frame[row * 256 + 4], 0x0F);
+            assert_eq!(frame[row * 256 + 5], 0x16);
+        }
+    }
+
+    fn render_sprite_frame(ppu: &mut Ppu) {
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        ppu.cycle = 0;
+        ppu.reset_frame_complete();
+        while !ppu.is_frame_complete() {
+            ppu.step();
+        }
+        for _ in 0..341 * 240 {
+            ppu.step();
+        }
+    }
+
+    fn sprite_ppu() -> Ppu {
+        // Tile 1 is solid colour 1, tile 2 solid colour 3, tile 3 only its left column
+        let mut chr = vec![0; 0x2000];
+        chr[16..24].fill(0xFF);
+        chr[32..48].fill(0xFF);
+        chr[48..56].fill(0x80);
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, chr, false);
+        ppu.oam_data.fill(0xFF); // all sprites off-screen
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x11;
+        ppu.palette_table[0x17] = 0x2A;
+        ppu.palette_table[0x11] = 0x30;
+        ppu.write(0x2001, 0x1E);
+        ppu
+    }
+
+    #[test]
+    fn test_sprite_rendering() {
+        let mut ppu = sprite_ppu();
+        ppu.oam_data[0..4].copy_from_slice(&[9, 2, 0x01, 20]);
+        ppu.oam_data[4..8].copy_from_slice(&[30, 3, 0x40, 40]); // flipped horizontally
+        render_sprite_frame(&mut ppu);
+
+        let frame = ppu.get_frame();
+        assert_eq!(frame[9 * 256 + 20], 0x0F);
+        assert_eq!(frame[10 * 256 + 20], 0x2A);
+        assert_eq!(frame[17 * 256 + 27], 0x2A);
+        assert_eq!(frame[10 * 256 + 28], 0x0F);
+        assert_eq!(frame[18 * 256 + 20], 0x0F);
+
+        assert_eq!(frame[31 * 256 + 40], 0x0F);
+        assert_eq!(frame[31 * 256 + 47], 0x30);
+    }
+
+    #[test]
+    fn test_sprite_priority_and_limit() {
+        let mut ppu = sprite_ppu();
+        ppu.ciram[0..0x3C0].fill(1); // background solid colour 1
+        ppu.oam_data[0..4].copy_from_slice(&[9, 2, 0x21, 0]); // behind background
+        // Nine sprites on one line, the ninth is dropped
+        for n in 1..10 {
+            ppu.oam_data[n * 4..n * 4 + 4].copy_from_slice(&[49, 2, 0x01, n as u8 * 8]);
+        }
+        render_sprite_frame(&mut ppu);
+
+        let frame = ppu.get_frame();
+        assert_eq!(frame[10 * 256], 0x11);
+        assert_eq!(frame[50 * 256 + 8], 0x2A);
+        assert_eq!(frame[50 * 256 + 64], 0x2A);
+        assert_eq!(frame[50 * 256 + 72], 0x11);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let mut ppu = sprite_ppu();
+        ppu.ciram[0..0x3C0].fill(1);
+        ppu.oam_data[0..4].copy_from_slice(&[99, 2, 0x00, 100]);
+        render_sprite_frame(&mut ppu);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        // Cleared on the pre-render line
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        ppu.cycle = 1;
+        ppu.step();
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        // Never at x=255, nor in the left column when it is clipped
+        ppu.oam_data[3] = 255;
+        render_sprite_frame(&mut ppu);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+        ppu.oam_data[3] = 0;
+        ppu.write(0x2001, 0x1A); // hide sprites in the left 8 pixels
+        render_sprite_frame(&mut ppu);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        // No background means no hit
+        ppu.ciram[0..0x3C0].fill(0);
+        ppu.oam_data[3] = 100;
+        ppu.write(0x2001, 0x1E);
+        render_sprite_frame(&mut ppu);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut ppu = sprite_ppu();
+        for n in 0..9 {
+            ppu.oam_data[n * 4..n * 4 + 4].copy_from_slice(&[49, 2, 0, n as u8 * 8]);
+        }
+        render_sprite_frame(&mut ppu);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+
+        // Ninth sprite off the line, but the diagonal scan reads sprite 9's
+        // tile byte as a Y coordinate: false positive
+        ppu.oam_data[8 * 4] = 0xF0;
+        ppu.oam_data[9 * 4..9 * 4 + 4].copy_from_slice(&[0xF0, 49, 0, 0]);
+        render_sprite_frame(&mut ppu);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+
+        ppu.oam_data[9 * 4 + 1] = 0xF0;
+        render_sprite_frame(&mut ppu);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+    }
+
+    fn run_to(ppu: &mut Ppu, scanline: i32, cycle: i32) {
+        while ppu.scanline != scanline || ppu.cycle != cycle {
+            ppu.step();
+        }
+    }
+
+    #[test]
+    fn test_vblank_and_nmi() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], false);
+        ppu.write(0x2000, 0x80);
+        run_to(&mut ppu, VBLANK_SCANLINE, 1);
+        assert!(!ppu.status.is_in_vblank());
+        ppu.step();
+        assert!(ppu.status.is_in_vblank());
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+
+        run_to(&mut ppu, PRE_RENDER_SCANLINE, 2);
+        assert!(!ppu.status.is_in_vblank());
+        assert!(!ppu.poll_nmi());
+    }
 
+    #[test]
+    fn test_nmi_enabled_during_vblank() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], false);
+        run_to(&mut ppu, 250, 0);
+        assert!(!ppu.poll_nmi());
+        ppu.write(0x2000, 0x80);
+        assert!(!ppu.poll_nmi());
+        assert!(ppu.poll_nmi());
 
+        // Toggling it again retriggers while the flag is still set
+        ppu.write(0x2000, 0x00);
+        ppu.write(0x2000, 0x80);
+        assert!(!ppu.poll_nmi());
+        assert!(ppu.poll_nmi());
+        ppu.read(0x2002);
+        ppu.write(0x2000, 0x00);
+        ppu.write(0x2000, 0x80);
+        assert!(!ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
 
+        // Disabling it on the dot vblank starts drops the NMI
+        run_to(&mut ppu, VBLANK_SCANLINE, 2);
+        ppu.write(0x2000, 0x00);
+        assert!(!ppu.poll_nmi());
+    }
 
+    #[test]
+    fn test_status_read_races_vblank() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], false);
+        ppu.write(0x2000, 0x80);
 
+        // One dot early: reads clear, no flag and no NMI this frame
+        run_to(&mut ppu, VBLANK_SCANLINE, 1);
+        assert_eq!(ppu.read(0x2002) & 0x80, 0);
+        ppu.step();
+        assert!(!ppu.status.is_in_vblank());
+        assert!(!ppu.poll_nmi());
 
+        // Same dot: reads set, NMI cancelled
+        run_to(&mut ppu, PRE_RENDER_SCANLINE, 0);
+        run_to(&mut ppu, VBLANK_SCANLINE, 2);
+        assert_eq!(ppu.read(0x2002) & 0x80, 0x80);
+        assert!(!ppu.poll_nmi());
 
+        // Later reads don't affect the NMI
+        run_to(&mut ppu, PRE_RENDER_SCANLINE, 0);
+        run_to(&mut ppu, VBLANK_SCANLINE, 10);
+        ppu.read(0x2002);
+        assert!(ppu.poll_nmi());
+    }
 
+    fn frame_dots(ppu: &mut Ppu) -> u32 {
+        let mut dots = 0;
+        ppu.reset_frame_complete();
+        while !ppu.is_frame_complete() {
+            ppu.step();
+            dots += 1;
+        }
+        dots
+    }
 
+    #[test]
+    fn test_odd_frame_skip() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], false);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
 
+        ppu.write(0x2001, 0x08);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262 - 1);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
+    }
+
+    #[test]
+    fn test_oam_addr_reset_while_rendering() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], false);
+        ppu.write(0x2003, 0x40);
+        run_to(&mut ppu, 10, 300);
+        assert_eq!(ppu.oam_addr, 0x40);
 
+        ppu.write(0x2001, 0x10);
+        run_to(&mut ppu, 11, 258);
+        assert_eq!(ppu.oam_addr, 0);
+    }
 
+    #[test]
+    fn test_nametable_mirroring() {
+        let mut ppu = Ppu::new(Mirroring::VERTICAL, vec![0; 0x2000], false);
+        assert_eq!(ppu.mirror_vram_addr(0x2405), 0x405);
+        assert_eq!(ppu.mirror_vram_addr(0x2805), 0x005);
+        assert_eq!(ppu.mirror_vram_addr(0x3C05), 0x405);
+
+        ppu.set_mirroring(Mirroring::HORIZONTAL);
+        assert_eq!(ppu.mirror_vram_addr(0x2405), 0x005);
+        assert_eq!(ppu.mirror_vram_addr(0x2C05), 0x405);
+
+        ppu.set_mirroring(Mirroring::SINGLE_SCREEN_B);
+        ppu.write(0x2006, 0x20);
+        ppu.write(0x2006, 0x10);
+        ppu.write(0x2007, 0x5A);
+        assert_eq!(ppu.ciram[0x410], 0x5A);
+        assert_eq!(ppu.read_nametable(0x2C10), 0x5A);
+
+        ppu.set_mirroring(Mirroring::SINGLE_SCREEN_A);
+        assert_eq!(ppu.read_nametable(0x2C10), 0);
+    }
+
+    #[test]
+    fn test_four_screen_vram() {
+        let mut ppu = Ppu::new(Mirroring::FOUR_SCREEN, vec![0; 0x2000], false);
+        for table in 0..4u16 {
+            ppu.write(0x2006, 0x20 + table as u8 * 4);
+            ppu.write(0x2006, 0x00);
+            ppu.write(0x2007, table as u8 + 1);
+        }
+        assert_eq!(ppu.four_screen_vram[0xC00], 4);
+        assert_eq!(ppu.read_nametable(0x2800), 3);
+        assert_eq!(ppu.read_nametable(0x3400), 2);
+        assert_eq!(ppu.ciram, [0; 0x800]);
+    }
+
+    #[test]
+    fn test_palette_ram() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], false);
+        let set_addr = |ppu: &mut Ppu, addr: u16| {
+            ppu.write(0x2006, (addr >> 8) as u8);
+            ppu.write(0x2006, addr as u8);
+        };
+
+        set_addr(&mut ppu, 0x3F10);
+        ppu.write(0x2007, 0x21);
+        ppu.write(0x2007, 0xFF);
+        assert_eq!(ppu.palette_table[0x00], 0x21);
+        assert_eq!(ppu.palette_table[0x11], 0x3F);
+
+        // $3F20-$3FFF mirror the 32 entries, reads aren't buffered and
+        // fill bits 6-7 from open bus (here the last $2006 write)
+        set_addr(&mut ppu, 0x3FE0);
+        assert_eq!(ppu.read(0x2007), 0xE0 | 0x21);
+        set_addr(&mut ppu, 0x3F3C);
+        ppu.write(0x2007, 0x05);
+        assert_eq!(ppu.palette_table[0x0C], 0x05);
+        set_addr(&mut ppu, 0x3F0C);
+        assert_eq!(ppu.read(0x2007) & 0x3F, 0x05);
+
+        // ...but they refill the buffer from the nametable underneath
+        ppu.ciram[0x700] = 0x99;
+        set_addr(&mut ppu, 0x3F00);
+        ppu.read(0x2007);
+        set_addr(&mut ppu, 0x2000);
+        assert_eq!(ppu.read(0x2007), 0x99);
+
+        // Every legal address reads without panicking
+        for addr in 0x3000..=0x3FFF {
+            set_addr(&mut ppu, addr);
+            ppu.read(0x2007);
+        }
+    }
+
+    #[test]
+    fn test_mask_effects() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], false);
+        ppu.palette_table[0] = 0x16;
+        ppu.write(0x2001, 0x20 | 0x01); // red emphasis, greyscale
+        run_to(&mut ppu, 1, 0);
+        assert_eq!(ppu.get_frame()[10], 0x10 | (0b001 << 6));
+
+        // Palette reads through $2007 are greyscaled too
+        ppu.write(0x2006, 0x3F);
+        ppu.write(0x2006, 0x00);
+        assert_eq!(ppu.read(0x2007) & 0x3F, 0x10);
+
+        ppu.set_region(Region::Pal);
+        ppu.write(0x2001, 0x20);
+        run_to(&mut ppu, 2, 0);
+        assert_eq!(ppu.get_frame()[256 + 10], 0x16 | (0b010 << 6));
+    }
+
+    #[test]
+    fn test_left_column_clipping() {
+        let mut ppu = sprite_ppu();
+        ppu.ciram[0..0x3C0].fill(1);
+        ppu.oam_data[0..4].copy_from_slice(&[9, 2, 0x01, 0]);
+        ppu.write(0x2001, 0x18); // both layers hidden in the left 8 pixels
+        render_sprite_frame(&mut ppu);
+
+        let frame = ppu.get_frame();
+        assert_eq!(frame[5 * 256 + 7], 0x0F);
+        assert_eq!(frame[5 * 256 + 8], 0x11);
+        assert_eq!(frame[12 * 256 + 7], 0x0F);
+
+        ppu.write(0x2001, 0x1C); // sprites only
+        render_sprite_frame(&mut ppu);
+        let frame = ppu.get_frame();
+        assert_eq!(frame[5 * 256 + 7], 0x0F);
+        assert_eq!(frame[12 * 256 + 7], 0x2A);
+    }
+
+    #[test]
+    fn test_region_frame_layout() {
+        let mut ppu = Ppu::new(Mirroring::HORIZONTAL, vec![0; 0x2000], false);
+        ppu.set_region(Region::Dendy);
+        ppu.write(0x2001, 0x08);
+        ppu.write(0x2000, 0x80);
+
+        // 312 lines, no odd-frame skip, vblank 20 lines before the pre-render line
+        assert_eq!(frame_dots(&mut ppu), 341 * 312);
+        assert_eq!(frame_dots(&mut ppu), 341 * 312);
+        run_to(&mut ppu, 241, 2);
+        assert!(!ppu.status.is_in_vblank());
+        run_to(&mut ppu, 291, 2);
+        assert!(ppu.status.is_in_vblank());
+        assert!(ppu.poll_nmi());
+        run_to(&mut ppu, 311, 2);
+        assert!(!ppu.status.is_in_vblank());
+
+        ppu.set_region(Region::Pal);
+        run_to(&mut ppu, 241, 2);
+        assert!(ppu.status.is_in_vblank());
+    }
+}
@@ -0,0 +1,147 @@
+// Console region: clock rates and frame layout
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+
+use crate::nes_header::Timing;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy, // PAL clocks and frame length with NTSC-style vblank and APU
+}
+
+// APU periods in CPU cycles
+#[rustfmt::skip]
+const NTSC_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+#[rustfmt::skip]
+const PAL_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+#[rustfmt::skip]
+const NTSC_DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+#[rustfmt::skip]
+const PAL_DMC_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+impl Region {
+    /// Region the cartridge was made for; multi-region games run as NTSC
+    pub fn from_timing(timing: Timing) -> Self {
+        match timing {
+            Timing::Ntsc | Timing::MultiRegion => Region::Ntsc,
+            Timing::Pal => Region::Pal,
+            Timing::Dendy => Region::Dendy,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    pub fn master_clock(self) -> u32 {
+        match self {
+            Region::Ntsc => 21_477_272,
+            Region::Pal | Region::Dendy => 26_601_712,
+        }
+    }
+
+    /// Master clocks per CPU cycle
+    pub fn cpu_divider(self) -> u32 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    /// Master clocks per PPU dot
+    pub fn ppu_divider(self) -> u32 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    /// Scanlines per frame, including the pre-render line
+    pub fn scanlines(self) -> i32 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// Scanline whose dot 1 sets the vblank flag. Dendy idles for 50 lines
+    /// after the picture, then has a 20-line vblank like NTSC.
+    pub fn vblank_scanline(self) -> i32 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /// Only the 2C02 drops a dot on odd frames
+    pub fn skips_odd_frame_dot(self) -> bool {
+        self == Region::Ntsc
+    }
+
+    /// PAL-style PPUs wire PPUMASK bit 5 to green and bit 6 to red
+    pub fn swaps_red_green_emphasis(self) -> bool {
+        self != Region::Ntsc
+    }
+
+    pub fn frame_rate(self) -> f64 {
+        let dots = 341.0 * self.scanlines() as f64;
+        self.master_clock() as f64 / self.ppu_divider() as f64 / dots
+    }
+
+    // Rate tables for the APU, which isn't emulated yet
+    #[allow(dead_code)]
+    pub fn noise_periods(self) -> &'static [u16; 16] {
+        match self {
+            Region::Pal => &PAL_NOISE_PERIODS,
+            Region::Ntsc | Region::Dendy => &NTSC_NOISE_PERIODS,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn dmc_rates(self) -> &'static [u16; 16] {
+        match self {
+            Region::Pal => &PAL_DMC_RATES,
+            Region::Ntsc | Region::Dendy => &NTSC_DMC_RATES,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_region_timing() {
+        assert_eq!(Region::from_timing(Timing::MultiRegion), Region::Ntsc);
+        assert_eq!(Region::from_name("PAL"), Some(Region::Pal));
+        assert_eq!(Region::from_name("secam"), None);
+
+        assert!((Region::Ntsc.frame_rate() - 60.1).abs() < 0.01);
+        assert!((Region::Pal.frame_rate() - 50.007).abs() < 0.01);
+        assert_eq!(Region::Dendy.frame_rate(), Region::Pal.frame_rate());
+
+        // CPU cycles per frame: 29780.67 NTSC, 33247.5 PAL, 35464 Dendy
+        let cycles = |r: Region| 341 * r.scanlines() as u32 * r.ppu_divider() / r.cpu_divider();
+        assert_eq!(cycles(Region::Ntsc), 29780);
+        assert_eq!(cycles(Region::Pal), 33247);
+        assert_eq!(cycles(Region::Dendy), 35464);
+
+        assert_eq!(Region::Dendy.dmc_rates()[0], 428);
+        assert_eq!(Region::Pal.noise_periods()[15], 3778);
+    }
+}
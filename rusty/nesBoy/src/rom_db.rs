@@ -0,0 +1,188 @@
+// Game database lookup by PRG+CHR hash, used to fix bad iNES headers
+// https://www.nesdev.org/wiki/NES_2.0_XML_Database
+
+use lazy_static::lazy_static;
+use sha1::{Digest, Sha1};
+
+use crate::nes_header::{ConsoleType, NesHeader, Timing};
+
+lazy_static! {
+    static ref BUNDLED_DB: RomDb =
+        RomDb::parse(include_str!("../nes20db.xml")).expect("bundled nes20db.xml is malformed");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomHashes {
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+}
+
+impl RomHashes {
+    /// Hash PRG-ROM followed by CHR-ROM, the way the database keys its entries
+    pub fn compute(prg_rom: &[u8], chr_rom: &[u8]) -> Self {
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(prg_rom);
+        crc.update(chr_rom);
+
+        let mut sha1 = Sha1::new();
+        sha1.update(prg_rom);
+        sha1.update(chr_rom);
+
+        RomHashes {
+            crc32: crc.finalize(),
+            sha1: sha1.finalize().into(),
+        }
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        self.sha1.iter().map(|b| format!("{:02X}", b)).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DbEntry {
+    pub title: String,
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: char, // 'H', 'V' or '4'
+    pub battery: bool,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub expansion: u8,
+}
+
+impl DbEntry {
+    /// Overwrite the header fields the database knows better about
+    pub fn apply(&self, header: &mut NesHeader) {
+        header.mapper = self.mapper;
+        header.submapper = self.submapper;
+        header.vertical_mirroring = self.mirroring == 'V';
+        header.four_screen_mode = self.mirroring == '4';
+        header.has_battery = self.battery;
+        header.prg_ram_size = self.prg_ram_size;
+        header.prg_nvram_size = self.prg_nvram_size;
+        header.chr_ram_size = self.chr_ram_size;
+        header.chr_nvram_size = self.chr_nvram_size;
+        header.timing = self.timing;
+        header.console_type = self.console_type;
+        header.default_expansion_device = self.expansion;
+    }
+
+    pub fn region(&self) -> &'static str {
+        match self.timing {
+            Timing::Ntsc => "NTSC",
+            Timing::Pal => "PAL",
+            Timing::MultiRegion => "Multi-region",
+            Timing::Dendy => "Dendy",
+        }
+    }
+}
+
+pub struct RomDb {
+    entries: Vec<DbEntry>,
+}
+
+impl RomDb {
+    /// The database compiled into the binary from nes20db.xml
+    pub fn bundled() -> &'static RomDb {
+        &BUNDLED_DB
+    }
+
+    pub fn parse(xml: &str) -> Result<Self, String> {
+        let doc = roxmltree::Document::parse(xml).map_err(|e| e.to_string())?;
+        let entries = doc
+            .root_element()
+            .children()
+            .filter(|n| n.has_tag_name("game"))
+            .map(Self::parse_game)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(RomDb { entries })
+    }
+
+    fn parse_game(game: roxmltree::Node) -> Result<DbEntry, String> {
+        let child = |name: &str| game.children().find(|n| n.has_tag_name(name));
+        let attr = |name: &str, key: &str| child(name).and_then(|n| n.attribute(key));
+        let num = |name: &str, key: &str| -> Result<usize, String> {
+            attr(name, key)
+                .map(|v| {
+                    v.parse()
+                        .map_err(|_| format!("bad {} {}: {}", name, key, v))
+                })
+                .unwrap_or(Ok(0))
+        };
+        let size = |name: &str| num(name, "size");
+
+        // nes20db stores the file name as a comment in front of the entry
+        let title = game
+            .children()
+            .find(|n| n.is_comment())
+            .and_then(|n| n.text())
+            .map(|t| t.trim().trim_end_matches(".nes").to_string())
+            .unwrap_or_default();
+
+        let crc32 = attr("rom", "crc32")
+            .and_then(|v| u32::from_str_radix(v, 16).ok())
+            .ok_or(format!("{}: missing <rom crc32>", title))?;
+        let sha1 = attr("rom", "sha1").and_then(parse_sha1);
+
+        let timing = match num("console", "region")? {
+            0 => Timing::Ntsc,
+            1 => Timing::Pal,
+            2 => Timing::MultiRegion,
+            _ => Timing::Dendy,
+        };
+        let console_type = match num("console", "type")? {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem {
+                ppu_type: num("vs", "ppu")? as u8,
+                hardware_type: num("vs", "hardware")? as u8,
+            },
+            2 => ConsoleType::Playchoice10,
+            t => ConsoleType::Extended(t as u8),
+        };
+
+        Ok(DbEntry {
+            title,
+            crc32,
+            sha1,
+            mapper: num("pcb", "mapper")? as u16,
+            submapper: num("pcb", "submapper")? as u8,
+            mirroring: attr("pcb", "mirroring")
+                .and_then(|m| m.chars().next())
+                .unwrap_or('H'),
+            battery: num("pcb", "battery")? != 0,
+            prg_ram_size: size("prgram")?,
+            prg_nvram_size: size("prgnvram")?,
+            chr_ram_size: size("chrram")?,
+            chr_nvram_size: size("chrnvram")?,
+            timing,
+            console_type,
+            expansion: num("expansion", "type")? as u8,
+        })
+    }
+
+    /// Find the entry for a ROM, matching on CRC32 and on SHA-1 when the
+    /// entry has one
+    pub fn lookup(&self, hashes: &RomHashes) -> Option<&DbEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.crc32 == hashes.crc32 && e.sha1.map(|s| s == hashes.sha1).unwrap_or(true))
+    }
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut out = [0u8; 20];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
@@ -1,135 +1,307 @@
-use std::fs::File;
-use std::io::prelude::*;
-
-#[derive(Debug, Clone)]
-struct NESHeader {
-    signature: [u8; 4], // Should be "NES" followed by MS-DOS EOF
-    prg_rom_size: u8,   // PRG ROM size in 16KB units
-    chr_rom_size: u8,   // CHR ROM size in 8KB units
-    flags6: u8,         // Mapper, mirroring, battery, trainer
-    flags7: u8,         // Mapper, VS/Playchoice, NES 2.0
-    flags8: u8,         // PRG-RAM size (rarely used)
-    flags9: u8,         // TV system (rarely used)
-    flags10: u8,        // TV system, PRG-RAM (rarely used)
-    padding: [u8; 5],   // Unused padding
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::archive;
+use crate::fds::{self, FDS_DISK_MAGIC, FDS_HEADER_MAGIC, FDS_SIDE_SIZE};
+use crate::nes_header::NesHeader;
+use crate::patch::{self, PatchError};
+use crate::ppu::Mirroring;
+use crate::rom_db::{DbEntry, RomDb, RomHashes};
+use crate::unif::{UNIF_MAGIC, UnifImage};
+
+pub const TRAINER_SIZE: usize = 512;
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    BadMagic,
+    TruncatedHeader,
+    TruncatedTrainer,
+    TruncatedPrg { expected: usize, found: usize },
+    TruncatedChr { expected: usize, found: usize },
+    MissingPrg,
+    UnsupportedMapper(u16),
+    BadNes20Size(&'static str),
+    Archive(String),
+    NoRomInArchive,
+    ArchiveEntryNotFound(String),
+    Patch(PatchError),
+    BadFdsImage(usize),
+    BadFdsBios(usize),
+    BadUnifChunk(usize),
+    MissingUnifBoard,
+    UnknownUnifBoard(String),
+    BadUnifMirroring(u8),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "{}", e),
+            RomError::BadMagic => write!(f, "not an iNES file (missing \"NES\\x1A\" signature)"),
+            RomError::TruncatedHeader => write!(f, "file is too short to hold an iNES header"),
+            RomError::TruncatedTrainer => write!(f, "file ends inside the 512-byte trainer"),
+            RomError::TruncatedPrg { expected, found } => write!(
+                f,
+                "PRG-ROM is truncated: header declares {} bytes, file has {}",
+                expected, found
+            ),
+            RomError::TruncatedChr { expected, found } => write!(
+                f,
+                "CHR-ROM is truncated: header declares {} bytes, file has {}",
+                expected, found
+            ),
+            RomError::MissingPrg => write!(f, "ROM has no PRG-ROM"),
+            RomError::UnsupportedMapper(mapper) => write!(f, "mapper {} is not supported", mapper),
+            RomError::BadNes20Size(what) => write!(f, "invalid NES 2.0 {} size", what),
+            RomError::Archive(e) => write!(f, "could not read archive: {}", e),
+            RomError::NoRomInArchive => {
+                write!(f, "archive contains no .nes, .unf or .fds file")
+            }
+            RomError::ArchiveEntryNotFound(name) => {
+                write!(f, "archive has no entry named {}", name)
+            }
+            RomError::Patch(e) => write!(f, "could not apply patch: {}", e),
+            RomError::BadFdsImage(len) => write!(
+                f,
+                "FDS image is {} bytes, not a whole number of {}-byte sides",
+                len, FDS_SIDE_SIZE
+            ),
+            RomError::BadFdsBios(len) => {
+                write!(f, "FDS BIOS must be 8192 bytes, got {}", len)
+            }
+            RomError::BadUnifChunk(offset) => {
+                write!(
+                    f,
+                    "UNIF chunk at offset {} runs past the end of the file",
+                    offset
+                )
+            }
+            RomError::MissingUnifBoard => write!(f, "UNIF file has no MAPR (board name) chunk"),
+            RomError::UnknownUnifBoard(board) => write!(f, "unknown UNIF board {}", board),
+            RomError::BadUnifMirroring(value) => write!(f, "invalid UNIF MIRR value {}", value),
+        }
+    }
+}
+
+impl std::error::Error for RomError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RomError::Io(e) => Some(e),
+            RomError::Patch(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> Self {
+        RomError::Io(e)
+    }
+}
+
+impl From<PatchError> for RomError {
+    fn from(e: PatchError) -> Self {
+        RomError::Patch(e)
+    }
 }
 
 pub struct RomLoader {
-    header: NESHeader,
+    header: NesHeader,
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
-    mapper: u8,
+    mapper: u16,
+    submapper: u8,
     pub vertical_mirroring: bool,
-    four_screen_mode: bool,
+    mirroring: Mirroring,
     has_battery: bool,
     has_trainer: bool,
+    trainer: Option<Vec<u8>>,
+    fds_image: Option<Vec<u8>>,
+    board: Option<String>, // UNIF board name
+    title: Option<String>, // UNIF NAME chunk
+    hashes: RomHashes,
+    db_entry: Option<DbEntry>,
+    header_corrected: bool,
+    patch: Option<PathBuf>, // patch file applied at load time
 }
 
 impl RomLoader {
-    /// Create a new RomLoader by loading a ROM file
-    pub fn new(filename: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut file = File::open(filename)?;
-        let mut header_bytes = [0u8; 16];
-        file.read_exact(&mut header_bytes)?;
-
-        // Validate the signature is "NES\x1A"
-        if &header_bytes[0..3] != b"NES" || header_bytes[3] != 0x1A {
-            return Err("Invalid NES header signature".into());
-        }
+    /// Create a new RomLoader by loading a ROM file, which may be zipped or gzipped.
+    /// A .ips/.ups/.bps patch with the same name next to it is applied automatically.
+    #[allow(dead_code)] // main.rs goes through `open` for --entry and --patch
+    pub fn new(filename: &str) -> Result<Self, RomError> {
+        Self::open(filename, None, None)
+    }
+
+    /// Like `new`, but picks `entry` out of a .zip instead of the first ROM in it
+    /// and applies `patch_file` instead of looking for a patch next to the ROM
+    pub fn open(
+        filename: &str,
+        entry: Option<&str>,
+        patch_file: Option<&str>,
+    ) -> Result<Self, RomError> {
+        let mut data = archive::read_rom_image(filename, entry)?;
 
-        let header = NESHeader {
-            signature: [
-                header_bytes[0],
-                header_bytes[1],
-                header_bytes[2],
-                header_bytes[3],
-            ],
-            prg_rom_size: header_bytes[4],
-            chr_rom_size: header_bytes[5],
-            flags6: header_bytes[6],
-            flags7: header_bytes[7],
-            flags8: header_bytes[8],
-            flags9: header_bytes[9],
-            flags10: header_bytes[10],
-            padding: [
-                header_bytes[11],
-                header_bytes[12],
-                header_bytes[13],
-                header_bytes[14],
-                header_bytes[15],
-            ],
+        let patch_path = match patch_file {
+            Some(path) => Some(path.into()),
+            None => patch::find_patch(filename),
         };
+        if let Some(patch_path) = &patch_path {
+            let patch_data = std::fs::read(patch_path)?;
+            data = patch::apply(&data, &patch_data)?;
+        }
 
-        // Extract flags
-        let mapper = (header.flags7 & 0xF0) | ((header.flags6 & 0xF0) >> 4);
-        let vertical_mirroring = (header.flags6 & 0x01) != 0;
-        let four_screen_mode = (header.flags6 & 0x08) != 0;
-        let has_battery = (header.flags6 & 0x02) != 0;
-        let has_trainer = (header.flags6 & 0x04) != 0;
-
-        // Skip trainer if present (512 bytes)
-        if has_trainer {
-            file.seek(std::io::SeekFrom::Current(512))?;
+        let mut rom = Self::from_bytes(&data)?;
+        rom.patch = patch_path;
+        Ok(rom)
+    }
+
+    /// Create a new RomLoader from an in-memory iNES, UNIF or FDS image
+    pub fn from_bytes(data: &[u8]) -> Result<Self, RomError> {
+        if data.starts_with(FDS_HEADER_MAGIC) || data.starts_with(FDS_DISK_MAGIC) {
+            return Self::from_fds(data);
+        }
+        if data.starts_with(UNIF_MAGIC) {
+            return Self::from_unif(data);
+        }
+
+        let header_bytes: &[u8; NesHeader::SIZE] = data
+            .get(..NesHeader::SIZE)
+            .and_then(|h| h.try_into().ok())
+            .ok_or(RomError::TruncatedHeader)?;
+
+        let header = NesHeader::parse(header_bytes)?;
+
+        let mut offset = NesHeader::SIZE;
+
+        // Trainer if present (512 bytes), mapped at $7000 on power-on
+        let mut trainer = None;
+        if header.has_trainer {
+            let bytes = data
+                .get(offset..offset + TRAINER_SIZE)
+                .ok_or(RomError::TruncatedTrainer)?;
+            trainer = Some(bytes.to_vec());
+            offset += TRAINER_SIZE;
         }
 
-        // Read PRG ROM (16KB units)
-        let prg_rom_bytes = header.prg_rom_size as usize * 16384;
-        let mut prg_rom = vec![0u8; prg_rom_bytes];
-        file.read_exact(&mut prg_rom)?;
-
-        // Read CHR ROM (8KB units) if present
-        let mut chr_rom = Vec::new();
-        if header.chr_rom_size > 0 {
-            let chr_rom_bytes = header.chr_rom_size as usize * 8192;
-            chr_rom.resize(chr_rom_bytes, 0);
-            file.read_exact(&mut chr_rom)?;
+        // Read PRG ROM
+        let prg_rom = data
+            .get(offset..offset + header.prg_rom_size)
+            .ok_or(RomError::TruncatedPrg {
+                expected: header.prg_rom_size,
+                found: data.len() - offset,
+            })?
+            .to_vec();
+        offset += header.prg_rom_size;
+
+        // Read CHR ROM if present
+        let chr_rom = data
+            .get(offset..offset + header.chr_rom_size)
+            .ok_or(RomError::TruncatedChr {
+                expected: header.chr_rom_size,
+                found: data.len() - offset,
+            })?
+            .to_vec();
+
+        Ok(Self::with_rom(header, prg_rom, chr_rom, trainer))
+    }
+
+    /// UNIF boards are translated to the mapper number an iNES dump would use
+    fn from_unif(data: &[u8]) -> Result<Self, RomError> {
+        let image = UnifImage::parse(data)?;
+        let header = image.header()?;
+
+        let mut rom = Self::with_rom(header, image.prg_rom, image.chr_rom, None);
+        rom.board = Some(image.board);
+        rom.title = image.name;
+        if let Some(mirroring) = image.mirroring {
+            rom.mirroring = mirroring;
         }
+        Ok(rom)
+    }
 
-        Ok(RomLoader {
+    fn with_rom(
+        mut header: NesHeader,
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        trainer: Option<Vec<u8>>,
+    ) -> Self {
+        // Known dumps get their header fixed up from the database
+        let hashes = RomHashes::compute(&prg_rom, &chr_rom);
+        let db_entry = RomDb::bundled().lookup(&hashes).cloned();
+        let mut header_corrected = false;
+        if let Some(entry) = &db_entry {
+            let before = header.clone();
+            entry.apply(&mut header);
+            header_corrected = before.mapper != header.mapper
+                || before.submapper != header.submapper
+                || before.vertical_mirroring != header.vertical_mirroring
+                || before.four_screen_mode != header.four_screen_mode
+                || before.has_battery != header.has_battery;
+        }
+
+        // Extract flags
+        let mapper = header.mapper;
+        let submapper = header.submapper;
+        let vertical_mirroring = header.vertical_mirroring;
+        let mirroring = header.mirroring();
+        let has_battery = header.has_battery;
+        let has_trainer = header.has_trainer;
+
+        RomLoader {
             header,
             prg_rom,
             chr_rom,
             mapper,
+            submapper,
             vertical_mirroring,
-            four_screen_mode,
+            mirroring,
             has_battery,
             has_trainer,
-        })
+            trainer,
+            fds_image: None,
+            board: None,
+            title: None,
+            hashes,
+            db_entry,
+            header_corrected,
+            patch: None,
+        }
     }
 
-    /// Print ROM information
-    pub fn print_info(&self) {
-        println!("ROM loaded successfully:");
-        println!(
-            "  PRG ROM Size: {} KB",
-            self.header.prg_rom_size as u32 * 16
-        );
-        println!("  CHR ROM Size: {} KB", self.header.chr_rom_size as u32 * 8);
-        println!("  Mapper Number: {}", self.mapper);
-        println!(
-            "  Mirroring: {}",
-            if self.vertical_mirroring {
-                "Vertical"
-            } else {
-                "Horizontal"
-            }
-        );
-        println!(
-            "  Four-screen VRAM: {}",
-            if self.four_screen_mode { "Yes" } else { "No" }
-        );
-        println!(
-            "  Battery-backed RAM: {}",
-            if self.has_battery { "Yes" } else { "No" }
-        );
-        println!(
-            "  Trainer Present: {}",
-            if self.has_trainer { "Yes" } else { "No" }
-        );
+    /// Disk images have no ROM of their own, the sides are kept whole for the drive
+    fn from_fds(data: &[u8]) -> Result<Self, RomError> {
+        let sides = fds::strip_header(data);
+        if sides.is_empty() || !sides.len().is_multiple_of(FDS_SIDE_SIZE) {
+            return Err(RomError::BadFdsImage(sides.len()));
+        }
+
+        let header = NesHeader::fds();
+        Ok(RomLoader {
+            mapper: header.mapper,
+            submapper: header.submapper,
+            vertical_mirroring: header.vertical_mirroring,
+            mirroring: header.mirroring(),
+            has_battery: header.has_battery,
+            has_trainer: header.has_trainer,
+            header,
+            prg_rom: Vec::new(),
+            chr_rom: Vec::new(),
+            trainer: None,
+            fds_image: Some(sides.to_vec()),
+            board: None,
+            title: None,
+            hashes: RomHashes::compute(sides, &[]),
+            db_entry: None,
+            header_corrected: false,
+            patch: None,
+        })
     }
 
     // Getters
-    pub fn header(&self) -> &NESHeader {
+    pub fn header(&self) -> &NesHeader {
         &self.header
     }
 
@@ -141,16 +313,23 @@ impl RomLoader {
         &self.chr_rom
     }
 
-    pub fn mapper(&self) -> u8 {
+    pub fn mapper(&self) -> u16 {
         self.mapper
     }
 
+    pub fn submapper(&self) -> u8 {
+        self.submapper
+    }
+
+    /// The header's vertical mirroring bit; `mirroring` has the layout in effect
+    #[allow(dead_code)] // the bus uses `mirroring`
     pub fn vertical_mirroring(&self) -> bool {
         self.vertical_mirroring
     }
 
-    pub fn four_screen_mode(&self) -> bool {
-        self.four_screen_mode
+    /// Nametable layout at power-on, before any mapper changes it
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
     }
 
     pub fn has_battery(&self) -> bool {
@@ -160,16 +339,116 @@ impl RomLoader {
     pub fn has_trainer(&self) -> bool {
         self.has_trainer
     }
+
+    pub fn trainer(&self) -> Option<&[u8]> {
+        self.trainer.as_deref()
+    }
+
+    pub fn fds_image(&self) -> Option<&[u8]> {
+        self.fds_image.as_deref()
+    }
+
+    pub fn board(&self) -> Option<&str> {
+        self.board.as_deref()
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    pub fn hashes(&self) -> &RomHashes {
+        &self.hashes
+    }
+
+    pub fn db_entry(&self) -> Option<&DbEntry> {
+        self.db_entry.as_ref()
+    }
+
+    pub fn header_corrected(&self) -> bool {
+        self.header_corrected
+    }
+
+    /// The .ips/.ups/.bps file applied when the ROM was opened, if any
+    pub fn patch(&self) -> Option<&Path> {
+        self.patch.as_deref()
+    }
 }
 
-// Example usage
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let rom = RomLoader::new("path/to/game.nes")?;
-    rom.print_info();
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ines(prg_banks: u8, chr_banks: u8, flags6: u8) -> Vec<u8> {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, prg_banks, chr_banks, flags6];
+        rom.resize(NesHeader::SIZE, 0);
+        rom
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let mut data = ines(1, 1, 0x01);
+        data.extend(vec![0xEA; 16384]);
+        data.extend(vec![0x55; 8192]);
+        let rom = RomLoader::from_bytes(&data).unwrap();
+        assert_eq!(rom.prg_rom().len(), 16384);
+        assert_eq!(rom.chr_rom()[0], 0x55);
+        assert!(rom.vertical_mirroring());
+    }
+
+    #[test]
+    fn test_database_corrects_header() {
+        // ff.nes ships with the battery bit cleared
+        let rom = RomLoader::new("ff.nes").unwrap();
+        assert_eq!(rom.hashes().crc32, 0x952E1DAD);
+        assert_eq!(rom.db_entry().unwrap().title, "Final Fantasy");
+        assert!(rom.has_battery());
+        assert_eq!(rom.header().prg_nvram_size, 8192);
+        assert!(rom.header_corrected());
+    }
 
-    // Access ROM data
-    println!("PRG ROM size: {} bytes", rom.prg_rom().len());
-    println!("CHR ROM size: {} bytes", rom.chr_rom().len());
+    #[test]
+    fn test_trainer_is_kept() {
+        let mut data = ines(1, 0, 0x04);
+        data.extend(vec![0x42; TRAINER_SIZE]);
+        data.extend(vec![0xEA; 16384]);
+        let rom = RomLoader::from_bytes(&data).unwrap();
+        assert_eq!(rom.trainer(), Some(&[0x42; TRAINER_SIZE][..]));
+        assert_eq!(rom.prg_rom()[0], 0xEA);
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let mut data = ines(1, 0, 0);
+        data[3] = 0;
+        assert!(matches!(
+            RomLoader::from_bytes(&data),
+            Err(RomError::BadMagic)
+        ));
+        assert!(matches!(
+            RomLoader::from_bytes(b"NES"),
+            Err(RomError::TruncatedHeader)
+        ));
+    }
 
-    Ok(())
+    #[test]
+    fn test_truncated_rom() {
+        let mut data = ines(2, 1, 0);
+        data.extend(vec![0; 16384]);
+        assert!(matches!(
+            RomLoader::from_bytes(&data),
+            Err(RomError::TruncatedPrg {
+                expected: 32768,
+                found: 16384
+            })
+        ));
+
+        data.extend(vec![0; 16384 + 100]);
+        assert!(matches!(
+            RomLoader::from_bytes(&data),
+            Err(RomError::TruncatedChr {
+                expected: 8192,
+                found: 100
+            })
+        ));
+    }
 }
@@ -0,0 +1,56 @@
+use bitflags::bitflags;
+
+bitflags! {
+
+    // 7  bit  0
+    // ---- ----
+    // VSO. ....
+    // |||| ||||
+    // |||+-++++- PPU open bus: returns stale PPU bus contents
+    // ||+------- Sprite overflow (more than 8 sprites on a scanline,
+    // ||         with a hardware bug causing false positives and negatives)
+    // |+-------- Sprite 0 hit: an opaque sprite 0 pixel overlaps an
+    // |          opaque background pixel
+    // +--------- Vertical blank has started (0: not in vblank; 1: in vblank).
+    //            Cleared after reading $2002 and at dot 1 of the pre-render line
+    pub struct StatusRegister: u8 {
+        const NOTUSED          = 0b00000001;
+        const NOTUSED2         = 0b00000010;
+        const NOTUSED3         = 0b00000100;
+        const NOTUSED4         = 0b00001000;
+        const NOTUSED5         = 0b00010000;
+        const SPRITE_OVERFLOW  = 0b00100000;
+        const SPRITE_ZERO_HIT  = 0b01000000;
+        const VBLANK_STARTED   = 0b10000000;
+    }
+}
+
+impl StatusRegister {
+    pub fn new() -> Self {
+        StatusRegister::from_bits_truncate(0b00000000)
+    }
+
+    pub fn set_vblank_status(&mut self, status: bool) {
+        self.set(StatusRegister::VBLANK_STARTED, status);
+    }
+
+    pub fn set_sprite_zero_hit(&mut self, status: bool) {
+        self.set(StatusRegister::SPRITE_ZERO_HIT, status);
+    }
+
+    pub fn set_sprite_overflow(&mut self, status: bool) {
+        self.set(StatusRegister::SPRITE_OVERFLOW, status);
+    }
+
+    pub fn reset_vblank_status(&mut self) {
+        self.remove(StatusRegister::VBLANK_STARTED);
+    }
+
+    pub fn is_in_vblank(&self) -> bool {
+        self.contains(StatusRegister::VBLANK_STARTED)
+    }
+
+    pub fn snapshot(&self) -> u8 {
+        self.bits
+    }
+}
@@ -0,0 +1,155 @@
+// nestest-style execution log: one line per instruction, taken before it runs
+// https://www.qmtpro.com/~nes/misc/nestest.log
+
+use crate::cpu::{Cpu, NON_READABLE_ADDR};
+use crate::opscode::{AddressingMode, OPSCODES_MAP, UNKNOWN_OPSCODE};
+
+pub fn trace(cpu: &mut Cpu) -> String {
+    let begin = cpu.pc;
+    let code = cpu.bus.read(begin);
+    let ops = OPSCODES_MAP.get(&code).copied().unwrap_or(&UNKNOWN_OPSCODE);
+    let mut hex_dump = vec![code];
+
+    let (mem_addr, stored_value) = match ops.mode {
+        AddressingMode::Immediate
+        | AddressingMode::NoneAddressing
+        | AddressingMode::Accumulator => (0, 0),
+        _ => {
+            let addr = operand_address(cpu, &ops.mode, begin.wrapping_add(1));
+            if is_readable(addr) {
+                (addr, cpu.bus.read(addr))
+            } else {
+                (addr, 0)
+            }
+        }
+    };
+
+    let operand = match ops.len {
+        1 => match ops.mode {
+            AddressingMode::Accumulator => String::from("A "),
+            _ => String::new(),
+        },
+        2 => {
+            let address = cpu.bus.read(begin.wrapping_add(1));
+            hex_dump.push(address);
+
+            match ops.mode {
+                AddressingMode::Immediate => format!("#${:02x}", address),
+                AddressingMode::ZeroPage => format!("${:02x} = {:02x}", mem_addr, stored_value),
+                AddressingMode::ZeroPage_X => format!(
+                    "${:02x},X @ {:02x} = {:02x}",
+                    address, mem_addr, stored_value
+                ),
+                AddressingMode::ZeroPage_Y => format!(
+                    "${:02x},Y @ {:02x} = {:02x}",
+                    address, mem_addr, stored_value
+                ),
+                AddressingMode::Indirect_X => format!(
+                    "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
+                    address,
+                    address.wrapping_add(cpu.x),
+                    mem_addr,
+                    stored_value
+                ),
+                AddressingMode::Indirect_Y => format!(
+                    "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
+                    address,
+                    mem_addr.wrapping_sub(cpu.y as u16),
+                    mem_addr,
+                    stored_value
+                ),
+                // Relative branch: show the target
+                _ => {
+                    let target = begin.wrapping_add(2).wrapping_add(address as i8 as u16);
+                    format!("${:04x}", target)
+                }
+            }
+        }
+        _ => {
+            let lo = cpu.bus.read(begin.wrapping_add(1));
+            let hi = cpu.bus.read(begin.wrapping_add(2));
+            hex_dump.push(lo);
+            hex_dump.push(hi);
+            let address = u16::from_le_bytes([lo, hi]);
+
+            match ops.mode {
+                AddressingMode::Absolute => format!("${:04x} = {:02x}", mem_addr, stored_value),
+                AddressingMode::Absolute_X => format!(
+                    "${:04x},X @ {:04x} = {:02x}",
+                    address, mem_addr, stored_value
+                ),
+                AddressingMode::Absolute_Y => format!(
+                    "${:04x},Y @ {:04x} = {:02x}",
+                    address, mem_addr, stored_value
+                ),
+                // JMP ($xxFF) fetches the high byte from $xx00, like the CPU does
+                _ if ops.code == 0x6C => {
+                    let hi_addr = (address & 0xFF00) | (address.wrapping_add(1) & 0x00FF);
+                    let jmp_addr =
+                        u16::from_le_bytes([cpu.bus.read(address), cpu.bus.read(hi_addr)]);
+                    format!("(${:04x}) = {:04x}", address, jmp_addr)
+                }
+                _ => format!("${:04x}", address),
+            }
+        }
+    };
+
+    let hex_str = hex_dump
+        .iter()
+        .map(|z| format!("{:02x}", z))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let asm_str = format!(
+        "{:04x}  {:8} {: >4} {}",
+        begin, hex_str, ops.mnemonic, operand
+    )
+    .trim()
+    .to_string();
+
+    format!(
+        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
+        asm_str, cpu.a, cpu.x, cpu.y, cpu.p, cpu.sp
+    )
+    .to_ascii_uppercase()
+}
+
+// Effective address of a memory operand stored at `addr`
+fn operand_address(cpu: &mut Cpu, mode: &AddressingMode, addr: u16) -> u16 {
+    match mode {
+        AddressingMode::ZeroPage => cpu.bus.read(addr) as u16,
+        AddressingMode::ZeroPage_X => cpu.bus.read(addr).wrapping_add(cpu.x) as u16,
+        AddressingMode::ZeroPage_Y => cpu.bus.read(addr).wrapping_add(cpu.y) as u16,
+        AddressingMode::Absolute => read_word(cpu, addr),
+        AddressingMode::Absolute_X => read_word(cpu, addr).wrapping_add(cpu.x as u16),
+        AddressingMode::Absolute_Y => read_word(cpu, addr).wrapping_add(cpu.y as u16),
+        AddressingMode::Indirect_X => {
+            let pointer = cpu.bus.read(addr).wrapping_add(cpu.x);
+            read_zero_page_word(cpu, pointer)
+        }
+        AddressingMode::Indirect_Y => {
+            let pointer = cpu.bus.read(addr);
+            read_zero_page_word(cpu, pointer).wrapping_add(cpu.y as u16)
+        }
+        _ => 0,
+    }
+}
+
+fn read_word(cpu: &mut Cpu, addr: u16) -> u16 {
+    u16::from_le_bytes([cpu.bus.read(addr), cpu.bus.read(addr.wrapping_add(1))])
+}
+
+// Pointers in zero page wrap around within it
+fn read_zero_page_word(cpu: &mut Cpu, pointer: u8) -> u16 {
+    let lo = cpu.bus.read(pointer as u16);
+    let hi = cpu.bus.read(pointer.wrapping_add(1) as u16);
+    u16::from_le_bytes([lo, hi])
+}
+
+// Reading PPU and controller registers has side effects, so the log doesn't
+fn is_readable(addr: u16) -> bool {
+    let addr = match addr {
+        0x2000..=0x3FFF => 0x2000 + addr % 8,
+        _ => addr,
+    };
+    !NON_READABLE_ADDR.contains(&addr)
+}
@@ -0,0 +1,266 @@
+// UNIF (Universal NES Image Format): a 32-byte header followed by tagged chunks
+// https://www.nesdev.org/wiki/UNIF
+
+use crate::nes_header::{ConsoleType, HeaderFormat, NesHeader, Timing};
+use crate::ppu::Mirroring;
+use crate::rom_loader::RomError;
+
+pub const UNIF_MAGIC: &[u8] = b"UNIF";
+const UNIF_HEADER_SIZE: usize = 32;
+
+// Board names with their vendor prefix (NES-, HVC-, UNL-, ...) stripped,
+// and the iNES mapper that implements them
+#[rustfmt::skip]
+const BOARDS: &[(&str, u16)] = &[
+    ("NROM", 0), ("NROM-128", 0), ("NROM-256", 0), ("RROM", 0), ("RROM-128", 0),
+    ("SAROM", 1), ("SBROM", 1), ("SCROM", 1), ("SEROM", 1), ("SGROM", 1), ("SKROM", 1),
+    ("SLROM", 1), ("SL1ROM", 1), ("SNROM", 1), ("SOROM", 1), ("SUROM", 1), ("SXROM", 1),
+    ("UNROM", 2), ("UOROM", 2),
+    ("CNROM", 3),
+    ("TBROM", 4), ("TEROM", 4), ("TFROM", 4), ("TGROM", 4), ("TKROM", 4), ("TLROM", 4),
+    ("TL1ROM", 4), ("TLSROM", 118), ("TKSROM", 118), ("TQROM", 119), ("TR1ROM", 4),
+    ("TSROM", 4), ("TVROM", 4), ("HKROM", 4),
+    ("EKROM", 5), ("ELROM", 5), ("ETROM", 5), ("EWROM", 5),
+    ("AMROM", 7), ("ANROM", 7), ("AN1ROM", 7), ("AOROM", 7),
+    ("PNROM", 9), ("PEEOROM", 9),
+    ("FJROM", 10), ("FKROM", 10),
+    ("CPROM", 13),
+    ("BNROM", 34),
+    ("GNROM", 66), ("MHROM", 66),
+    ("Sachen-8259A", 141), ("Sachen-8259B", 138), ("Sachen-8259C", 139),
+    ("Sachen-8259D", 137), ("SA-72007", 145), ("SA-72008", 133), ("SA-0036", 149),
+    ("SA-0037", 148), ("TC-U01-1.5M", 147), ("H2288", 123),
+];
+
+const VENDOR_PREFIXES: &[&str] = &["NES-", "HVC-", "UNL-", "BMC-", "BTL-", "KONAMI-"];
+
+/// The iNES mapper for a UNIF board name such as "NES-TLROM" or "UNL-Sachen-8259A"
+pub fn board_mapper(board: &str) -> Option<u16> {
+    let name = VENDOR_PREFIXES
+        .iter()
+        .find_map(|p| board.strip_prefix(p))
+        .unwrap_or(board);
+    BOARDS
+        .iter()
+        .find(|(b, _)| b.eq_ignore_ascii_case(name))
+        .map(|&(_, mapper)| mapper)
+}
+
+pub struct UnifImage {
+    pub board: String,
+    pub name: Option<String>,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub mirroring: Option<Mirroring>, // None when the mapper controls it (MIRR 5)
+    battery: bool,
+    timing: Timing,
+}
+
+impl UnifImage {
+    pub fn parse(data: &[u8]) -> Result<Self, RomError> {
+        if !data.starts_with(UNIF_MAGIC) {
+            return Err(RomError::BadMagic);
+        }
+        if data.len() < UNIF_HEADER_SIZE {
+            return Err(RomError::TruncatedHeader);
+        }
+
+        // PRG0-PRGF and CHR0-CHRF may come in any order but are laid out by index
+        let mut prg: [Option<&[u8]>; 16] = [None; 16];
+        let mut chr: [Option<&[u8]>; 16] = [None; 16];
+        let mut board = None;
+        let mut name = None;
+        let mut mirroring = None;
+        let mut battery = false;
+        let mut timing = Timing::Ntsc;
+
+        let mut offset = UNIF_HEADER_SIZE;
+        while offset < data.len() {
+            let chunk_header = data
+                .get(offset..offset + 8)
+                .ok_or(RomError::BadUnifChunk(offset))?;
+            let id = &chunk_header[0..4];
+            let len = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap()) as usize;
+            let body = data
+                .get(offset + 8..offset + 8 + len)
+                .ok_or(RomError::BadUnifChunk(offset))?;
+            offset += 8 + len;
+
+            match id {
+                b"MAPR" => board = Some(c_string(body)),
+                b"NAME" => name = Some(c_string(body)),
+                b"MIRR" => {
+                    if let Some(&value) = body.first() {
+                        mirroring = mirr(value)?;
+                    }
+                }
+                b"BATR" => battery = body.first().map(|&b| b != 0).unwrap_or(true),
+                b"TVCI" => {
+                    timing = match body.first() {
+                        Some(1) => Timing::Pal,
+                        Some(2) => Timing::MultiRegion,
+                        _ => Timing::Ntsc,
+                    }
+                }
+                [b'P', b'R', b'G', n] => {
+                    if let Some(i) = hex_digit(*n) {
+                        prg[i] = Some(body);
+                    }
+                }
+                [b'C', b'H', b'R', n] => {
+                    if let Some(i) = hex_digit(*n) {
+                        chr[i] = Some(body);
+                    }
+                }
+                // CRCs, dumper info, comments and the like
+                _ => {}
+            }
+        }
+
+        Ok(UnifImage {
+            board: board.ok_or(RomError::MissingUnifBoard)?,
+            name: name.filter(|n| !n.is_empty()),
+            prg_rom: prg
+                .iter()
+                .flatten()
+                .flat_map(|c| c.iter().copied())
+                .collect(),
+            chr_rom: chr
+                .iter()
+                .flatten()
+                .flat_map(|c| c.iter().copied())
+                .collect(),
+            mirroring,
+            battery,
+            timing,
+        })
+    }
+
+    /// Synthesize the header an equivalent iNES dump would have
+    pub fn header(&self) -> Result<NesHeader, RomError> {
+        let mapper = board_mapper(&self.board)
+            .ok_or_else(|| RomError::UnknownUnifBoard(self.board.clone()))?;
+
+        Ok(NesHeader {
+            format: HeaderFormat::Unif,
+            prg_rom_size: self.prg_rom.len(),
+            chr_rom_size: self.chr_rom.len(),
+            mapper,
+            submapper: 0,
+            vertical_mirroring: self.mirroring == Some(Mirroring::VERTICAL),
+            four_screen_mode: self.mirroring == Some(Mirroring::FOUR_SCREEN),
+            has_battery: self.battery,
+            has_trainer: false,
+            // UNIF doesn't record WRAM, assume the usual 8KB
+            prg_ram_size: if self.battery { 0 } else { 0x2000 },
+            prg_nvram_size: if self.battery { 0x2000 } else { 0 },
+            chr_ram_size: if self.chr_rom.is_empty() { 0x2000 } else { 0 },
+            chr_nvram_size: 0,
+            timing: self.timing,
+            console_type: ConsoleType::Nes,
+            misc_roms: 0,
+            default_expansion_device: 0,
+            raw: [0; 16],
+        })
+    }
+}
+
+// MIRR: 0 horizontal, 1 vertical, 2/3 single-screen A/B, 4 four-screen,
+// 5 mapper-controlled
+fn mirr(value: u8) -> Result<Option<Mirroring>, RomError> {
+    Ok(Some(match value {
+        0 => Mirroring::HORIZONTAL,
+        1 => Mirroring::VERTICAL,
+        2 => Mirroring::SINGLE_SCREEN_A,
+        3 => Mirroring::SINGLE_SCREEN_B,
+        4 => Mirroring::FOUR_SCREEN,
+        5 => return Ok(None),
+        _ => return Err(RomError::BadUnifMirroring(value)),
+    }))
+}
+
+// Text chunks are NUL-terminated, but not always
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+fn hex_digit(c: u8) -> Option<usize> {
+    (c as char).to_digit(16).map(|d| d as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend((body.len() as u32).to_le_bytes());
+        out.extend(body);
+        out
+    }
+
+    #[test]
+    fn test_parse_chunks() {
+        let mut data = b"UNIF".to_vec();
+        data.extend(7u32.to_le_bytes());
+        data.resize(UNIF_HEADER_SIZE, 0);
+        data.extend(chunk(b"MAPR", b"NES-NROM-256\0"));
+        data.extend(chunk(b"PRG1", &[0x22; 16384]));
+        data.extend(chunk(b"PRG0", &[0x11; 16384]));
+        data.extend(chunk(b"CHR0", &[0x33; 8192]));
+        data.extend(chunk(b"MIRR", &[1]));
+        data.extend(chunk(b"NAME", b"Test Cart\0"));
+
+        let image = UnifImage::parse(&data).unwrap();
+        assert_eq!(image.board, "NES-NROM-256");
+        assert_eq!(image.name.as_deref(), Some("Test Cart"));
+        assert_eq!(image.prg_rom.len(), 32768);
+        assert_eq!(image.prg_rom[0], 0x11);
+        assert_eq!(image.prg_rom[16384], 0x22);
+
+        let header = image.header().unwrap();
+        assert_eq!(header.format, HeaderFormat::Unif);
+        assert_eq!(header.mapper, 0);
+        assert_eq!(header.chr_rom_size, 8192);
+        assert!(header.vertical_mirroring);
+
+        data.extend(chunk(b"PRG2", &[0; 100]));
+        data.truncate(data.len() - 10);
+        assert!(matches!(
+            UnifImage::parse(&data),
+            Err(RomError::BadUnifChunk(_))
+        ));
+    }
+
+    #[test]
+    fn test_mirr_values() {
+        let image = |mirr: u8| {
+            let mut data = b"UNIF".to_vec();
+            data.resize(UNIF_HEADER_SIZE, 0);
+            data.extend(chunk(b"MAPR", b"NES-NROM-128\0"));
+            data.extend(chunk(b"MIRR", &[mirr]));
+            UnifImage::parse(&data)
+        };
+        for (mirr, expected) in [
+            (0, Some(Mirroring::HORIZONTAL)),
+            (1, Some(Mirroring::VERTICAL)),
+            (2, Some(Mirroring::SINGLE_SCREEN_A)),
+            (3, Some(Mirroring::SINGLE_SCREEN_B)),
+            (4, Some(Mirroring::FOUR_SCREEN)),
+            (5, None),
+        ] {
+            assert_eq!(image(mirr).unwrap().mirroring, expected, "MIRR {}", mirr);
+        }
+        assert!(matches!(image(6), Err(RomError::BadUnifMirroring(6))));
+    }
+
+    #[test]
+    fn test_board_mapper() {
+        assert_eq!(board_mapper("NES-TLROM"), Some(4));
+        assert_eq!(board_mapper("HVC-SNROM"), Some(1));
+        assert_eq!(board_mapper("UNL-Sachen-8259A"), Some(141));
+        assert_eq!(board_mapper("UNROM"), Some(2));
+        assert_eq!(board_mapper("BMC-Unheard-Of"), None);
+    }
+}